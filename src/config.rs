@@ -0,0 +1,412 @@
+use crate::*;
+
+use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/***************************
+    Runtime configuration
+****************************/
+
+static NETWORK: OnceLock<NetworkConfig> = OnceLock::new();
+static RPC: OnceLock<RpcConfig> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub rpc_url: String,
+    pub exchange_contract: String,
+    pub farm_contract: String,
+    pub stablecoins: Vec<String>,
+}
+
+impl NetworkConfig {
+    // NEAR_NETWORK selects the profile (defaults to testnet), the other variables override it
+    pub fn from_env() -> IndexerResult<NetworkConfig> {
+        let name = env::var("NEAR_NETWORK").unwrap_or_else(|_| String::from("testnet"));
+        let network = Network::from_name(&name)
+            .ok_or_else(|| IndexerError::Config(format!("unknown NEAR_NETWORK: {}", name)))?;
+
+        let rpc_url = env_or_default("NEAR_RPC_URL", network.rpc_url())?;
+        let exchange_contract =
+            env_or_default("REF_EXCHANGE_CONTRACT", network.exchange_contract())?;
+        let farm_contract = env_or_default("REF_FARM_CONTRACT", network.farm_contract())?;
+        let stablecoins = match env::var("STABLECOIN_CONTRACTS") {
+            Ok(value) => value.split(',').map(|s| s.trim().to_string()).collect(),
+            Err(_) => network
+                .stablecoins()
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+
+        Ok(NetworkConfig {
+            name: network.value().to_string(),
+            rpc_url,
+            exchange_contract,
+            farm_contract,
+            stablecoins,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    // Tried in order, the network's RPC URL first
+    pub endpoints: Vec<String>,
+    // Maximum number of view calls in flight during a refresh
+    pub concurrency: usize,
+    pub timeout: Duration,
+    // Retries after the first attempt, each one walks the endpoints again
+    pub max_retries: u32,
+    // Doubled after every retry
+    pub retry_delay: Duration,
+    // Consecutive failures before an endpoint is moved to the back of the list
+    pub failure_threshold: u64,
+    pub unhealthy_cooldown: Duration,
+    // Requests per second allowed on each endpoint, zero is unlimited
+    pub rate_limits: Vec<f64>,
+    // Fixture file every call is appended to
+    pub record: Option<String>,
+    // Fixture file calls are answered from, without touching the network
+    pub replay: Option<String>,
+}
+
+impl RpcConfig {
+    pub fn from_env() -> IndexerResult<RpcConfig> {
+        let concurrency = env_number("RPC_CONCURRENCY", 8)?;
+
+        if concurrency == 0 {
+            return Err(IndexerError::Config(String::from(
+                "RPC_CONCURRENCY must be at least 1",
+            )));
+        }
+
+        let mut endpoints = vec![network().rpc_url.clone()];
+        if let Ok(value) = env::var("NEAR_RPC_FALLBACK_URLS") {
+            endpoints.extend(
+                value
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty()),
+            );
+        }
+
+        // RPC_RATE_LIMIT applies to every endpoint, RPC_RATE_LIMITS overrides it by position
+        let default_rate = env_rate("RPC_RATE_LIMIT", "10")?;
+        let mut rate_limits = vec![default_rate; endpoints.len()];
+        if let Ok(value) = env::var("RPC_RATE_LIMITS") {
+            for (idx, rate) in value.split(',').enumerate().take(endpoints.len()) {
+                rate_limits[idx] = parse_rate("RPC_RATE_LIMITS", rate.trim())?;
+            }
+        }
+
+        let record = env::var("RPC_RECORD").ok();
+        let replay = env::var("RPC_REPLAY").ok();
+
+        if record.is_some() && replay.is_some() {
+            return Err(IndexerError::Config(String::from(
+                "RPC_RECORD and RPC_REPLAY can't be used together",
+            )));
+        }
+
+        Ok(RpcConfig {
+            endpoints,
+            concurrency: concurrency as usize,
+            timeout: env_millis("RPC_TIMEOUT_MS", 10_000)?,
+            max_retries: env_number("RPC_MAX_RETRIES", 3)? as u32,
+            retry_delay: env_millis("RPC_RETRY_DELAY_MS", 250)?,
+            failure_threshold: env_number("RPC_FAILURE_THRESHOLD", 3)?.max(1),
+            unhealthy_cooldown: env_secs("RPC_UNHEALTHY_COOLDOWN", 30)?,
+            rate_limits,
+            record,
+            replay,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisConfig {
+    // host:port
+    pub hostname: String,
+    pub password: String,
+    pub tls: bool,
+}
+
+impl RedisConfig {
+    pub fn from_env() -> IndexerResult<RedisConfig> {
+        Ok(RedisConfig {
+            hostname: env_or_default("REDIS_HOSTNAME", None)?,
+            password: env::var("REDIS_PASSWORD").unwrap_or_default(),
+            //if Redis server needs secure connection
+            tls: env::var("IS_TLS").is_ok(),
+        })
+    }
+
+    pub fn url(&self) -> String {
+        self.format_url(&self.password)
+    }
+
+    // Safe to log, the password is masked
+    pub fn redacted_url(&self) -> String {
+        if self.password.is_empty() {
+            self.format_url("")
+        } else {
+            self.format_url("***")
+        }
+    }
+
+    fn format_url(&self, password: &str) -> String {
+        let uri_scheme = if self.tls { "rediss" } else { "redis" };
+
+        format!("{}://:{}@{}", uri_scheme, password, self.hostname)
+    }
+}
+
+// Keeps the password out of `{:?}` output
+impl fmt::Debug for RedisConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.redacted_url())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MongoCollections {
+    pub tokens: String,
+    pub farms: String,
+    pub pools: String,
+    pub prices: String,
+    pub blocks: String,
+    pub pool_history: String,
+    pub farm_history: String,
+    pub token_history: String,
+}
+
+impl MongoCollections {
+    // Each name can be overridden with MONGO_<NAME>_COLLECTION
+    pub fn from_env() -> MongoCollections {
+        let name = |key: &str, default: &str| {
+            env::var(format!("MONGO_{}_COLLECTION", key)).unwrap_or_else(|_| default.to_string())
+        };
+
+        MongoCollections {
+            tokens: name("TOKENS", "ft_metadata"),
+            farms: name("FARMS", "farms"),
+            pools: name("POOLS", "pools"),
+            prices: name("PRICES", "prices"),
+            blocks: name("BLOCKS", "blocks"),
+            pool_history: name("POOL_HISTORY", "pool_history"),
+            farm_history: name("FARM_HISTORY", "farm_history"),
+            token_history: name("TOKEN_HISTORY", "token_history"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoConfig {
+    pub uri: String,
+    pub database: String,
+    pub app_name: String,
+    pub tls: bool,
+    pub tls_ca_file: Option<String>,
+    // Credentials given apart from the URI
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub auth_source: Option<String>,
+    pub collections: MongoCollections,
+}
+
+impl MongoConfig {
+    pub fn from_env() -> IndexerResult<MongoConfig> {
+        Ok(MongoConfig {
+            uri: env_or_default("MONGO_URI", Some("mongodb://localhost:27017"))?,
+            database: env_or_default("MONGO_DATABASE", Some("db"))?,
+            app_name: env_or_default("MONGO_APP_NAME", Some("ref-indexer"))?,
+            tls: env_flag("MONGO_TLS", false),
+            tls_ca_file: env::var("MONGO_TLS_CA_FILE").ok(),
+            username: env::var("MONGO_USERNAME").ok(),
+            password: env::var("MONGO_PASSWORD").ok(),
+            auth_source: env::var("MONGO_AUTH_SOURCE").ok(),
+            collections: MongoCollections::from_env(),
+        })
+    }
+
+    // Safe to log, credentials embedded in the URI are masked
+    pub fn redacted_uri(&self) -> String {
+        match (self.uri.find("://"), self.uri.rfind('@')) {
+            (Some(scheme), Some(at)) if at > scheme => {
+                format!("{}***{}", &self.uri[..scheme + 3], &self.uri[at..])
+            }
+            _ => self.uri.clone(),
+        }
+    }
+}
+
+// Keeps the credentials out of `{:?}` output
+impl fmt::Debug for MongoConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.redacted_uri(), self.database)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // How long snapshots are kept, zero keeps them forever
+    pub history_retention: Duration,
+    // Redis holds its history in memory, so it keeps a shorter window unless told otherwise
+    pub redis_history_retention: Duration,
+    // Only read when the backend uses Redis
+    pub redis: Option<RedisConfig>,
+    // Only read when the backend uses Mongo
+    pub mongo: Option<MongoConfig>,
+}
+
+impl StorageConfig {
+    // STORAGE_BACKEND is one of redis (default), mongo or both
+    pub fn from_env() -> IndexerResult<StorageConfig> {
+        let name = env::var("STORAGE_BACKEND").unwrap_or_else(|_| String::from("redis"));
+        let backend = StorageBackend::from_name(&name)
+            .ok_or_else(|| IndexerError::Config(format!("unknown STORAGE_BACKEND: {}", name)))?;
+
+        let redis = match backend.uses_redis() {
+            true => Some(RedisConfig::from_env()?),
+            false => None,
+        };
+        let mongo = match backend.uses_mongo() {
+            true => Some(MongoConfig::from_env()?),
+            false => None,
+        };
+
+        let history_retention = env_number("HISTORY_RETENTION", 30 * 24 * 3600)?;
+        let redis_history_retention = match env::var("HISTORY_RETENTION") {
+            Ok(_) => env_number("REDIS_HISTORY_RETENTION", history_retention)?,
+            Err(_) => env_number("REDIS_HISTORY_RETENTION", 7 * 24 * 3600)?,
+        };
+
+        Ok(StorageConfig {
+            backend,
+            history_retention: Duration::from_secs(history_retention),
+            redis_history_retention: Duration::from_secs(redis_history_retention),
+            redis,
+            mongo,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    // Time allowed to each dependency check
+    pub timeout: Duration,
+    // A dataset is stale once its last successful refresh is older than this many intervals
+    pub staleness_factor: u64,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> IndexerResult<HealthConfig> {
+        Ok(HealthConfig {
+            timeout: env_millis("HEALTH_TIMEOUT_MS", 2000)?,
+            staleness_factor: env_number("HEALTH_STALENESS_FACTOR", 3)?.max(1),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    pub tokens_interval: Duration,
+    pub farms_interval: Duration,
+    pub pools_interval: Duration,
+    pub jitter: Duration,
+    // How long metadata of tokens outside the whitelist is reused by pool refreshes
+    pub metadata_ttl: Duration,
+}
+
+impl SchedulerConfig {
+    // Intervals and jitter are in seconds, an interval of 0 disables that dataset
+    pub fn from_env() -> IndexerResult<SchedulerConfig> {
+        Ok(SchedulerConfig {
+            enabled: env_flag("SCHEDULER_ENABLED", true),
+            tokens_interval: env_secs("REFRESH_TOKENS_INTERVAL", 3600)?,
+            farms_interval: env_secs("REFRESH_FARMS_INTERVAL", 300)?,
+            pools_interval: env_secs("REFRESH_POOLS_INTERVAL", 60)?,
+            jitter: env_secs("REFRESH_JITTER", 10)?,
+            metadata_ttl: env_secs("TOKEN_METADATA_TTL", 86400)?,
+        })
+    }
+
+    pub fn interval(&self, dataset: &Dataset) -> Duration {
+        match *dataset {
+            Dataset::Tokens => self.tokens_interval,
+            Dataset::Farms => self.farms_interval,
+            Dataset::Pools => self.pools_interval,
+        }
+    }
+}
+
+fn env_number(key: &str, default: u64) -> IndexerResult<u64> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse::<u64>()
+            .map_err(|_| IndexerError::Config(format!("invalid {}: {}", key, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_secs(key: &str, default: u64) -> IndexerResult<Duration> {
+    Ok(Duration::from_secs(env_number(key, default)?))
+}
+
+fn env_millis(key: &str, default: u64) -> IndexerResult<Duration> {
+    Ok(Duration::from_millis(env_number(key, default)?))
+}
+
+fn parse_rate(key: &str, value: &str) -> IndexerResult<f64> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate >= 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(IndexerError::Config(format!("invalid {}: {}", key, value))),
+    }
+}
+
+fn env_rate(key: &str, default: &str) -> IndexerResult<f64> {
+    let value = env::var(key).unwrap_or_else(|_| default.to_string());
+    parse_rate(key, &value)
+}
+
+pub fn env_flag(key: &str, default: bool) -> bool {
+    match env::var(key) {
+        Ok(value) => !matches!(value.to_lowercase().as_str(), "0" | "false" | "no" | "off"),
+        Err(_) => default,
+    }
+}
+
+fn env_or_default(key: &str, default: Option<&str>) -> IndexerResult<String> {
+    match (env::var(key), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(value)) => Ok(value.to_string()),
+        (Err(_), None) => Err(IndexerError::Config(format!(
+            "missing environment variable {}",
+            key
+        ))),
+    }
+}
+
+// Called once from `rocket()`, before anything reads `network()`, so the RPC URL can be
+// pointed at the mock RPC
+pub fn init_network(config: NetworkConfig) -> &'static NetworkConfig {
+    NETWORK.get_or_init(|| config)
+}
+
+pub fn network() -> &'static NetworkConfig {
+    NETWORK.get_or_init(|| NetworkConfig::from_env().expect("invalid network configuration"))
+}
+
+pub fn init_rpc() -> IndexerResult<&'static RpcConfig> {
+    let config = RpcConfig::from_env()?;
+    Ok(RPC.get_or_init(|| config))
+}
+
+pub fn rpc() -> &'static RpcConfig {
+    RPC.get_or_init(|| RpcConfig::from_env().expect("invalid rpc configuration"))
+}
@@ -1,13 +1,146 @@
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Localnet,
+    Custom,
+}
+
+impl Network {
+    pub fn from_name(name: &str) -> Option<Network> {
+        match name.to_lowercase().as_str() {
+            "mainnet" => Some(Network::Mainnet),
+            "testnet" => Some(Network::Testnet),
+            "localnet" | "sandbox" => Some(Network::Localnet),
+            "custom" => Some(Network::Custom),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match *self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Localnet => "localnet",
+            Network::Custom => "custom",
+        }
+    }
+
+    // Custom networks have no defaults, everything must come from the environment
+    pub fn rpc_url(&self) -> Option<&str> {
+        match *self {
+            Network::Mainnet => Some("https://rpc.mainnet.near.org"),
+            Network::Testnet => Some("https://rpc.testnet.near.org"),
+            Network::Localnet => Some("http://localhost:3030"),
+            Network::Custom => None,
+        }
+    }
+
+    pub fn exchange_contract(&self) -> Option<&str> {
+        match *self {
+            Network::Mainnet => Some("v2.ref-finance.near"),
+            Network::Testnet => Some("ref-finance-101.testnet"),
+            Network::Localnet => Some("ref-finance.test.near"),
+            Network::Custom => None,
+        }
+    }
+
+    pub fn farm_contract(&self) -> Option<&str> {
+        match *self {
+            Network::Mainnet => Some("v2.ref-farming.near"),
+            Network::Testnet => Some("v2.ref-farming.testnet"),
+            Network::Localnet => Some("ref-farming.test.near"),
+            Network::Custom => None,
+        }
+    }
+
+    // Tokens treated as $1 when deriving prices from pool reserves
+    pub fn stablecoins(&self) -> Vec<&str> {
+        match *self {
+            Network::Mainnet => vec![
+                "usdt.tether-token.near",
+                "dac17f958d2ee523a2206206994597c13d831ec7.factory.bridge.near",
+                "17208628f84f5d6ad33f0da3bbbeb27ffcb398eac501a31bd6ad2011e36133a1",
+                "a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48.factory.bridge.near",
+                "6b175474e89094c44da98b954eedeac495271d0f.factory.bridge.near",
+            ],
+            Network::Testnet => vec![
+                "usdt.fakes.testnet",
+                "usdc.fakes.testnet",
+                "dai.fakes.testnet",
+            ],
+            Network::Localnet | Network::Custom => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Redis,
+    Mongo,
+    // Writes go to both, reads are served from Redis
+    Both,
+}
+
+impl StorageBackend {
+    pub fn from_name(name: &str) -> Option<StorageBackend> {
+        match name.to_lowercase().as_str() {
+            "redis" => Some(StorageBackend::Redis),
+            "mongo" | "mongodb" => Some(StorageBackend::Mongo),
+            "both" => Some(StorageBackend::Both),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match *self {
+            StorageBackend::Redis => "redis",
+            StorageBackend::Mongo => "mongo",
+            StorageBackend::Both => "both",
+        }
+    }
+
+    pub fn uses_redis(&self) -> bool {
+        matches!(*self, StorageBackend::Redis | StorageBackend::Both)
+    }
+
+    pub fn uses_mongo(&self) -> bool {
+        matches!(*self, StorageBackend::Mongo | StorageBackend::Both)
+    }
+}
+
 pub enum Contracts {
     RefExchange,
     RefFarm,
 }
 
 impl Contracts {
+    pub fn value(&self) -> &str {
+        let network = crate::config::network();
+
+        match *self {
+            Contracts::RefExchange => &network.exchange_contract,
+            Contracts::RefFarm => &network.farm_contract,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dataset {
+    Tokens,
+    Farms,
+    Pools,
+}
+
+impl Dataset {
+    pub fn all() -> Vec<Dataset> {
+        vec![Dataset::Tokens, Dataset::Farms, Dataset::Pools]
+    }
+
     pub fn value(&self) -> &str {
         match *self {
-            Contracts::RefExchange => "ref-finance-101.testnet",
-            Contracts::RefFarm => "v2.ref-farming.testnet",
+            Dataset::Tokens => "tokens",
+            Dataset::Farms => "farms",
+            Dataset::Pools => "pools",
         }
     }
 }
@@ -0,0 +1,153 @@
+use crate::*;
+
+use std::fmt;
+
+use near_jsonrpc_client::errors::JsonRpcError;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+/***************************
+    Errors
+****************************/
+
+#[derive(Debug)]
+pub enum IndexerError {
+    /// The RPC node failed or rejected a call.
+    Rpc(String),
+    /// A contract returned data we couldn't decode.
+    Decode(String),
+    /// Redis or Mongo failed.
+    Storage(String),
+    NotFound(String),
+    BadRequest(String),
+    /// The request is valid but the data to answer it isn't loaded yet.
+    Unavailable(String),
+    Config(String),
+}
+
+pub type IndexerResult<T> = Result<T, IndexerError>;
+
+impl IndexerError {
+    pub fn kind(&self) -> &str {
+        match *self {
+            IndexerError::Rpc(_) => "rpc",
+            IndexerError::Decode(_) => "decode",
+            IndexerError::Storage(_) => "storage",
+            IndexerError::NotFound(_) => "not_found",
+            IndexerError::BadRequest(_) => "bad_request",
+            IndexerError::Unavailable(_) => "unavailable",
+            IndexerError::Config(_) => "config",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match *self {
+            IndexerError::Rpc(_) | IndexerError::Decode(_) => Status::BadGateway,
+            IndexerError::Storage(_) | IndexerError::Unavailable(_) => Status::ServiceUnavailable,
+            IndexerError::NotFound(_) => Status::NotFound,
+            IndexerError::BadRequest(_) => Status::BadRequest,
+            IndexerError::Config(_) => Status::InternalServerError,
+        }
+    }
+
+    // Counted in the storage error metric
+    pub fn storage(backend: &str, message: impl fmt::Display) -> IndexerError {
+        metrics().storage_errors.with_label_values(&[backend]).inc();
+
+        IndexerError::Storage(format!("{}: {}", backend, message))
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            IndexerError::Rpc(message)
+            | IndexerError::Decode(message)
+            | IndexerError::Storage(message)
+            | IndexerError::NotFound(message)
+            | IndexerError::BadRequest(message)
+            | IndexerError::Unavailable(message)
+            | IndexerError::Config(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for IndexerError {}
+
+impl<E> From<JsonRpcError<E>> for IndexerError
+where
+    JsonRpcError<E>: fmt::Display,
+{
+    fn from(err: JsonRpcError<E>) -> Self {
+        IndexerError::Rpc(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for IndexerError {
+    fn from(err: serde_json::Error) -> Self {
+        IndexerError::Decode(err.to_string())
+    }
+}
+
+impl From<redis::RedisError> for IndexerError {
+    fn from(err: redis::RedisError) -> Self {
+        IndexerError::storage("redis", err)
+    }
+}
+
+impl From<mongodb::error::Error> for IndexerError {
+    fn from(err: mongodb::error::Error) -> Self {
+        IndexerError::storage("mongo", err)
+    }
+}
+
+// Errors are answered with `{"error": kind, "message": ...}` and a matching status code
+impl<'r> Responder<'r, 'static> for IndexerError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        println!("ERR_{}: {}", self.kind().to_uppercase(), self.message());
+
+        let body = json!({
+            "error": self.kind(),
+            "message": self.message(),
+        });
+
+        Response::build_from(Json(body).respond_to(request)?)
+            .status(self.status())
+            .ok()
+    }
+}
+
+// Rocket's own failures (unknown routes, unparsable parameters) get the same body. Guard failures
+// come in as 422 and are reported as bad requests, like ours
+#[catch(default)]
+pub fn default_catcher(
+    status: Status,
+    request: &Request,
+) -> (Status, Json<rocket::serde::json::Value>) {
+    let (status, kind) = match status.code {
+        404 => (status, "not_found"),
+        400 | 422 => (Status::BadRequest, "bad_request"),
+        500..=599 => (status, "internal"),
+        _ => (status, "http"),
+    };
+
+    let message = match status.code {
+        404 => format!("no route for {} {}", request.method(), request.uri()),
+        400 => format!("invalid request {} {}", request.method(), request.uri()),
+        _ => status.reason_lossy().to_lowercase(),
+    };
+
+    println!("ERR_{}: {}", kind.to_uppercase(), message);
+
+    let body = json!({
+        "error": kind,
+        "message": message,
+    });
+
+    (status, Json(body))
+}
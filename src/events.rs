@@ -0,0 +1,206 @@
+use crate::*;
+
+use tokio::sync::{broadcast, OnceCell};
+
+/***************************
+    Update stream
+****************************/
+
+// Events a subscriber may fall behind by before it's told to reload
+const FEED_CAPACITY: usize = 64;
+
+pub struct UpdateFeed {
+    sender: broadcast::Sender<UpdateEvent>,
+    // Latest pools and farms by id, as the feed last published them, so a refresh is diffed
+    // without reading the store back
+    pools: Mutex<BTreeMap<String, PoolInfo>>,
+    farms: Mutex<BTreeMap<String, FarmInfo>>,
+    loaded: OnceCell<()>,
+}
+
+impl UpdateFeed {
+    pub fn new() -> UpdateFeed {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+
+        UpdateFeed {
+            sender,
+            pools: Mutex::new(BTreeMap::new()),
+            farms: Mutex::new(BTreeMap::new()),
+            loaded: OnceCell::new(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UpdateEvent> {
+        self.sender.subscribe()
+    }
+
+    // Starts from what was stored before this process, so the first refresh after a restart
+    // only publishes what changed since. Retried on the next refresh when the store fails
+    pub async fn load(&self, store: &dyn Store) -> IndexerResult<()> {
+        self.loaded
+            .get_or_try_init(|| async {
+                let pools = store.get_pools().await?;
+                let farms = store.get_farms().await?;
+
+                *self.pools.lock().unwrap() = pools_by_id(&pools);
+                *self.farms.lock().unwrap() = farms_by_id(&farms);
+
+                IndexerResult::Ok(())
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    // Nothing is sent when no one listens or nothing changed
+    fn publish(&self, event: UpdateEvent) {
+        if event.pools.is_empty() && event.farms.is_empty() && event.removed.is_empty() {
+            return;
+        }
+
+        let _ = self.sender.send(event);
+    }
+
+    pub fn publish_pools(&self, block: &BlockInfo, pools: &[PoolInfo]) {
+        let current = pools_by_id(pools);
+        let (changed, removed) = {
+            let mut previous = self.pools.lock().unwrap();
+            let changes = diff(&previous, &current);
+            *previous = current;
+            changes
+        };
+
+        self.publish(UpdateEvent {
+            dataset: Dataset::Pools.value().to_string(),
+            block: block.clone(),
+            pools: changed,
+            farms: Vec::new(),
+            removed,
+        });
+    }
+
+    // Farms of seeds listed in `failures` weren't fetched, they are kept as last published
+    // instead of being reported as removed
+    pub fn publish_farms(&self, block: &BlockInfo, farms: &[FarmInfo], failures: &[FetchFailure]) {
+        let failed_seeds = failed_items(failures, Methods::ListFarmsBySeeds);
+
+        let mut current = farms_by_id(farms);
+        let (changed, removed) = {
+            let mut previous = self.farms.lock().unwrap();
+
+            for (id, farm) in previous.iter() {
+                if failed_seeds.contains(&farm.seed_id) && !current.contains_key(id) {
+                    current.insert(id.clone(), farm.clone());
+                }
+            }
+
+            let changes = diff(&previous, &current);
+            *previous = current;
+            changes
+        };
+
+        self.publish(UpdateEvent {
+            dataset: Dataset::Farms.value().to_string(),
+            block: block.clone(),
+            pools: Vec::new(),
+            farms: changed,
+            removed,
+        });
+    }
+}
+
+fn pools_by_id(pools: &[PoolInfo]) -> BTreeMap<String, PoolInfo> {
+    pools
+        .iter()
+        .filter_map(|pool| Some((pool.id?.to_string(), pool.clone())))
+        .collect()
+}
+
+fn farms_by_id(farms: &[FarmInfo]) -> BTreeMap<String, FarmInfo> {
+    farms
+        .iter()
+        .map(|farm| (farm.farm_id.clone(), farm.clone()))
+        .collect()
+}
+
+// Items that are new or differ from the previous refresh, and ids that disappeared
+fn diff<T: PartialEq + Clone>(
+    previous: &BTreeMap<String, T>,
+    current: &BTreeMap<String, T>,
+) -> (Vec<T>, Vec<String>) {
+    let changed = changed_entities(previous, current, T::eq)
+        .into_iter()
+        .map(|(_, item)| item)
+        .collect();
+    let removed = removed_ids(previous, current, |_| false);
+
+    (changed, removed)
+}
+
+#[derive(FromForm, Default)]
+pub struct EventQuery {
+    pub pool_id: Vec<u64>,
+    pub token: Vec<String>,
+}
+
+impl EventQuery {
+    fn matches_pool(&self, pool: &PoolInfo) -> bool {
+        (self.pool_id.is_empty() || pool.id.is_some_and(|id| self.pool_id.contains(&id)))
+            && (self.token.is_empty()
+                || pool
+                    .token_account_ids
+                    .iter()
+                    .any(|token| self.token.contains(token)))
+    }
+
+    // Farms match through the pool behind their LP seed, their reward token or their single token seed
+    fn matches_farm(&self, farm: &FarmInfo) -> bool {
+        (self.pool_id.is_empty()
+            || seed_pool_id(&farm.seed_id).is_some_and(|id| self.pool_id.contains(&id)))
+            && (self.token.is_empty()
+                || self.token.contains(&farm.reward_token)
+                || self.token.contains(&farm.seed_id))
+    }
+
+    // The tokens of a removed item are unknown, so removals only honour `pool_id`
+    fn matches_removed(&self, dataset: &str, id: &str) -> bool {
+        if self.pool_id.is_empty() {
+            return true;
+        }
+
+        dataset == Dataset::Pools.value()
+            && id.parse::<u64>().is_ok_and(|id| self.pool_id.contains(&id))
+    }
+
+    // Narrows an event down to the subscription, None when nothing is left
+    pub fn apply(&self, event: &UpdateEvent) -> Option<UpdateEvent> {
+        let filtered = UpdateEvent {
+            dataset: event.dataset.clone(),
+            block: event.block.clone(),
+            pools: event
+                .pools
+                .iter()
+                .filter(|pool| self.matches_pool(pool))
+                .cloned()
+                .collect(),
+            farms: event
+                .farms
+                .iter()
+                .filter(|farm| self.matches_farm(farm))
+                .cloned()
+                .collect(),
+            removed: event
+                .removed
+                .iter()
+                .filter(|id| self.matches_removed(&event.dataset, id))
+                .cloned()
+                .collect(),
+        };
+
+        if filtered.pools.is_empty() && filtered.farms.is_empty() && filtered.removed.is_empty() {
+            return None;
+        }
+
+        Some(filtered)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::*;
+
+use std::future::Future;
+use std::time::Instant;
+
+/***************************
+    Health checks
+****************************/
+
+// Runs one dependency check within the configured timeout
+async fn check<F>(config: &HealthConfig, future: F) -> HealthCheck
+where
+    F: Future<Output = IndexerResult<()>>,
+{
+    let started = Instant::now();
+
+    let error = match tokio::time::timeout(config.timeout, future).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!("timed out after {:?}", config.timeout)),
+    };
+
+    HealthCheck {
+        ok: error.is_none(),
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        age_secs: None,
+        error,
+    }
+}
+
+// A dataset is fresh when its last successful refresh is within `staleness_factor` intervals.
+// Datasets that aren't refreshed by this process are reported but never fail the check.
+fn freshness(config: &HealthConfig, enabled: bool, status: &RefreshStatus) -> HealthCheck {
+    let age_secs = status
+        .last_success_at
+        .map(|at| now_secs().saturating_sub(at));
+
+    if !enabled || status.interval_secs == 0 {
+        return HealthCheck {
+            ok: true,
+            age_secs,
+            ..Default::default()
+        };
+    }
+
+    let max_age = status.interval_secs * config.staleness_factor;
+    let error = match age_secs {
+        None => Some(String::from("never refreshed")),
+        Some(age) if age > max_age => Some(format!("stale, max age is {}s", max_age)),
+        Some(_) => None,
+    };
+
+    HealthCheck {
+        ok: error.is_none(),
+        latency_ms: None,
+        age_secs,
+        error,
+    }
+}
+
+pub async fn readiness(
+    config: &HealthConfig,
+    redis: Option<&RedisPool>,
+    mongo: Option<&MongoPool>,
+    scheduler: &Scheduler,
+) -> Readiness {
+    let (redis_check, mongo_check, rpc_check) = futures::join!(
+        async {
+            match redis {
+                Some(redis) => Some(check(config, redis.ping()).await),
+                None => None,
+            }
+        },
+        async {
+            match mongo {
+                Some(mongo) => Some(check(config, mongo.ping()).await),
+                None => None,
+            }
+        },
+        check(config, rpc_pool().probe()),
+    );
+
+    let mut checks: BTreeMap<String, HealthCheck> = BTreeMap::new();
+
+    if let Some(redis_check) = redis_check {
+        checks.insert(String::from("redis"), redis_check);
+    }
+    if let Some(mongo_check) = mongo_check {
+        checks.insert(String::from("mongo"), mongo_check);
+    }
+    checks.insert(String::from("rpc"), rpc_check);
+
+    for (dataset, status) in scheduler.status() {
+        checks.insert(
+            format!("freshness:{}", dataset),
+            freshness(config, scheduler.enabled(), &status),
+        );
+    }
+
+    Readiness {
+        ready: checks.values().all(|check| check.ok),
+        checks,
+    }
+}
@@ -0,0 +1,208 @@
+use crate::*;
+
+use std::cmp::Ordering;
+
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+
+/***************************
+    List query options
+****************************/
+
+#[derive(FromFormField, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(FromFormField, Clone, Copy)]
+pub enum PoolSort {
+    Id,
+    Tvl,
+    Fee,
+}
+
+#[derive(FromFormField, Clone, Copy)]
+pub enum FarmSort {
+    Id,
+    Apr,
+}
+
+#[derive(FromFormField, Clone, Copy)]
+pub enum TokenSort {
+    Id,
+    Symbol,
+    Price,
+}
+
+#[derive(FromForm, Default)]
+pub struct PoolQuery {
+    pub token: Option<String>,
+    pub kind: Option<String>,
+    pub farming: Option<bool>,
+    pub sort: Option<PoolSort>,
+    pub order: Option<SortOrder>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(FromForm, Default)]
+pub struct FarmQuery {
+    pub status: Option<String>,
+    pub reward_token: Option<String>,
+    pub seed_id: Option<String>,
+    pub sort: Option<FarmSort>,
+    pub order: Option<SortOrder>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(FromForm, Default)]
+pub struct TokenQuery {
+    pub symbol: Option<String>,
+    pub sort: Option<TokenSort>,
+    pub order: Option<SortOrder>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+// The body stays a plain JSON array, paging details travel in the headers
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub next_offset: Option<usize>,
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Paged<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Json(self.items).respond_to(request)?;
+
+        response.set_raw_header("X-Total-Count", self.total.to_string());
+        if let Some(next_offset) = self.next_offset {
+            response.set_raw_header("X-Next-Offset", next_offset.to_string());
+        }
+
+        Ok(response)
+    }
+}
+
+pub fn paginate<T>(items: Vec<T>, offset: Option<usize>, limit: Option<usize>) -> Paged<T> {
+    let total = items.len();
+    let offset = offset.unwrap_or_default().min(total);
+    let end = match limit {
+        Some(limit) => offset.saturating_add(limit).min(total),
+        None => total,
+    };
+
+    let items: Vec<T> = items.into_iter().skip(offset).take(end - offset).collect();
+
+    Paged {
+        items,
+        total,
+        next_offset: if end < total { Some(end) } else { None },
+    }
+}
+
+// Missing values sort last regardless of the order
+fn compare_optional(a: Option<f64>, b: Option<f64>, order: SortOrder) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => with_order(a.partial_cmp(&b).unwrap_or(Ordering::Equal), order),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+fn with_order(ordering: Ordering, order: SortOrder) -> Ordering {
+    if order == SortOrder::Desc {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+pub fn filter_pools(pools: Vec<PoolInfo>, query: &PoolQuery) -> Vec<PoolInfo> {
+    let mut pools: Vec<PoolInfo> = pools
+        .into_iter()
+        .filter(|pool| match &query.token {
+            Some(token) => pool.token_account_ids.contains(token),
+            None => true,
+        })
+        .filter(|pool| match &query.kind {
+            Some(kind) => pool.pool_kind.eq_ignore_ascii_case(kind),
+            None => true,
+        })
+        .filter(|pool| match query.farming {
+            Some(farming) => pool.farming.unwrap_or_default() == farming,
+            None => true,
+        })
+        .collect();
+
+    let order = query.order.unwrap_or(SortOrder::Asc);
+
+    match query.sort.unwrap_or(PoolSort::Id) {
+        PoolSort::Id => pools.sort_by(|a, b| with_order(a.id.cmp(&b.id), order)),
+        PoolSort::Fee => pools.sort_by(|a, b| with_order(a.total_fee.cmp(&b.total_fee), order)),
+        PoolSort::Tvl => pools.sort_by(|a, b| compare_optional(a.tvl, b.tvl, order)),
+    }
+
+    pools
+}
+
+pub fn filter_farms(farms: Vec<FarmInfo>, query: &FarmQuery) -> Vec<FarmInfo> {
+    let mut farms: Vec<FarmInfo> = farms
+        .into_iter()
+        .filter(|farm| match &query.status {
+            Some(status) => farm.farm_status.eq_ignore_ascii_case(status),
+            None => true,
+        })
+        .filter(|farm| match &query.reward_token {
+            Some(reward_token) => farm.reward_token == *reward_token,
+            None => true,
+        })
+        .filter(|farm| match &query.seed_id {
+            Some(seed_id) => farm.seed_id == *seed_id,
+            None => true,
+        })
+        .collect();
+
+    let order = query.order.unwrap_or(SortOrder::Asc);
+
+    match query.sort.unwrap_or(FarmSort::Id) {
+        FarmSort::Id => farms.sort_by(|a, b| with_order(a.farm_id.cmp(&b.farm_id), order)),
+        FarmSort::Apr => farms.sort_by(|a, b| compare_optional(a.apr, b.apr, order)),
+    }
+
+    farms
+}
+
+pub fn filter_tokens(
+    tokens: BTreeMap<String, FungibleTokenMetadata>,
+    query: &TokenQuery,
+) -> Vec<(String, FungibleTokenMetadata)> {
+    let mut tokens: Vec<(String, FungibleTokenMetadata)> = tokens
+        .into_iter()
+        .filter(|(_, metadata)| match &query.symbol {
+            Some(symbol) => metadata.symbol.eq_ignore_ascii_case(symbol),
+            None => true,
+        })
+        .collect();
+
+    let order = query.order.unwrap_or(SortOrder::Asc);
+
+    match query.sort.unwrap_or(TokenSort::Id) {
+        TokenSort::Id => tokens.sort_by(|a, b| with_order(a.0.cmp(&b.0), order)),
+        TokenSort::Symbol => tokens.sort_by(|a, b| with_order(a.1.symbol.cmp(&b.1.symbol), order)),
+        TokenSort::Price => tokens.sort_by(|a, b| {
+            let price = |metadata: &FungibleTokenMetadata| {
+                metadata
+                    .price
+                    .as_ref()
+                    .and_then(|price| price.parse::<f64>().ok())
+            };
+            compare_optional(price(&a.1), price(&b.1), order)
+        }),
+    }
+
+    tokens
+}
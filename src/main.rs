@@ -2,33 +2,71 @@
 extern crate rocket;
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use near_jsonrpc_client::methods::query::RpcQueryResponse;
 use near_jsonrpc_client::{methods, JsonRpcClient};
 use near_jsonrpc_primitives::types::query::QueryResponseKind;
-use near_primitives::types::{BlockReference, Finality, FunctionArgs};
+use near_primitives::types::{AccountId, BlockId, BlockReference, Finality, FunctionArgs};
 use near_primitives::views::QueryRequest;
 
 use derive_redis_json::RedisJsonValue;
+use futures::stream::{self, StreamExt};
 use rocket::serde::json::{json, Json};
+use rocket::{Build, Rocket};
 use serde::{Deserialize, Serialize};
 use serde_json::from_slice;
 
+mod config;
 mod consts;
+mod errors;
+mod events;
+mod health;
+mod listing;
+mod metadata;
+mod metrics;
+#[cfg(test)]
+mod mock_rpc;
 mod models;
 mod mongo_impl;
+mod pricing;
 mod redis_impl;
+mod replay;
 mod routes;
+mod routing;
+mod rpc;
+mod scheduler;
+mod store;
+mod swap;
+#[cfg(test)]
+mod tests;
 mod utils;
 
+use config::*;
 use consts::*;
+use errors::*;
+use events::*;
+use health::*;
+use listing::*;
+use metadata::*;
+use metrics::*;
+#[cfg(test)]
+use mock_rpc::*;
 use models::*;
 use mongo_impl::*;
+use pricing::*;
 use redis_impl::*;
+use replay::*;
+use routing::*;
+use rpc::*;
+use scheduler::*;
+use store::*;
+use swap::*;
 use utils::*;
 
-async fn get_seeds() -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+async fn get_seeds(block: &BlockInfo) -> IndexerResult<HashMap<String, String>> {
     let args = FunctionArgs::from(
         json!({
             "from_index": 0u64,
@@ -38,7 +76,13 @@ async fn get_seeds() -> Result<HashMap<String, String>, Box<dyn std::error::Erro
         .into_bytes(),
     );
 
-    let response = call_view(Contracts::RefFarm.value(), Methods::ListSeeds.value(), args).await?;
+    let response = call_view(
+        block,
+        Contracts::RefFarm.value(),
+        Methods::ListSeeds.value(),
+        args,
+    )
+    .await?;
 
     let mut seeds: HashMap<String, String> = HashMap::new();
 
@@ -49,8 +93,38 @@ async fn get_seeds() -> Result<HashMap<String, String>, Box<dyn std::error::Erro
     Ok(seeds)
 }
 
-async fn get_farms() -> Result<Vec<FarmInfo>, Box<dyn std::error::Error>> {
-    let seeds = get_seeds().await.unwrap_or_default();
+async fn get_seed_farms(block: &BlockInfo, seed_id: &str) -> IndexerResult<Vec<FarmInfo>> {
+    let args = FunctionArgs::from(
+        json!({
+            "seed_id": seed_id,
+        })
+        .to_string()
+        .into_bytes(),
+    );
+
+    let response = call_view(
+        block,
+        Contracts::RefFarm.value(),
+        Methods::ListFarmsBySeeds.value(),
+        args,
+    )
+    .await?;
+
+    let mut farms: Vec<FarmInfo> = Vec::new();
+
+    if let QueryResponseKind::CallResult(result) = response.kind {
+        farms = from_slice::<Vec<FarmInfo>>(&result.result)?;
+    }
+
+    Ok(farms)
+}
+
+// Seeds that can't be listed are recorded in `failures` and skipped
+async fn get_farms(
+    block: &BlockInfo,
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<Vec<FarmInfo>> {
+    let seeds = get_seeds(block).await?;
 
     if seeds.is_empty() {
         println!("ERR_FETCHING_SEEDS");
@@ -59,25 +133,18 @@ async fn get_farms() -> Result<Vec<FarmInfo>, Box<dyn std::error::Error>> {
 
     let mut farms: Vec<FarmInfo> = Vec::new();
 
-    for (key, _) in &seeds {
-        let args = FunctionArgs::from(
-            json!({
-                "seed_id": key,
-            })
-            .to_string()
-            .into_bytes(),
-        );
-
-        let response = call_view(
-            Contracts::RefFarm.value(),
-            Methods::ListFarmsBySeeds.value(),
-            args,
-        )
-        .await?;
-
-        if let QueryResponseKind::CallResult(result) = response.kind {
-            let res: Vec<FarmInfo> = from_slice::<Vec<FarmInfo>>(&result.result)?;
-            farms.extend(res);
+    for (key, seed_amount) in &seeds {
+        match get_seed_farms(block, key).await {
+            Ok(mut res) => {
+                for farm in res.iter_mut() {
+                    farm.seed_amount = Some(seed_amount.clone());
+                }
+                farms.extend(res);
+            }
+            Err(err) => {
+                println!("ERR_FETCHING_FARMS of {}: {}", key, err);
+                failures.push(FetchFailure::new(Methods::ListFarmsBySeeds, key, &err));
+            }
         }
     }
 
@@ -88,11 +155,17 @@ async fn get_farms() -> Result<Vec<FarmInfo>, Box<dyn std::error::Error>> {
     Ok(farms)
 }
 
-async fn get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
+// `cached` holds the metadata already stored and `fetched` what earlier refreshes fetched, only
+// tokens missing from both are fetched
+async fn get_pools(
+    block: &BlockInfo,
+    cached: &BTreeMap<String, FungibleTokenMetadata>,
+    fetched: &TokenMetadataCache,
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<(Vec<PoolInfo>, BTreeMap<String, TokenInfo>)> {
     let mut pools: Vec<PoolInfo> = Vec::new();
-    let token_metadata: BTreeMap<String, FungibleTokenMetadata> =
-        get_whitelisted_tokens().await.unwrap_or_default();
-    let seeds = internal_farm_seeds().await.unwrap_or_default();
+    let whitelisted_tokens = get_whitelisted_token_ids(block).await?;
+    let seeds = internal_farm_seeds(block, failures).await?;
 
     if seeds.is_empty() {
         println!("ERR_FETCHING_SEEDS");
@@ -101,7 +174,7 @@ async fn get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
     let method_name = Methods::NumPools.value();
 
     let args = FunctionArgs::from(json!({}).to_string().into_bytes());
-    let response = call_view(Contracts::RefExchange.value(), method_name, args).await?;
+    let response = call_view(block, Contracts::RefExchange.value(), method_name, args).await?;
 
     let mut num_pools: u64 = 0;
     if let QueryResponseKind::CallResult(result) = response.kind {
@@ -120,6 +193,7 @@ async fn get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
         );
 
         let response = call_view(
+            block,
             Contracts::RefExchange.value(),
             Methods::GetPools.value(),
             args,
@@ -134,6 +208,13 @@ async fn get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
         }
     }
 
+    // Every token of the refresh is looked up once, whitelisted or not
+    let mut tokens: Vec<String> = whitelisted_tokens.clone();
+    for pool in pools.iter() {
+        tokens.extend(pool.token_account_ids.iter().cloned());
+    }
+    let all_metadata = get_token_metadata(block, &tokens, cached, fetched, failures).await?;
+
     for (idx, pool) in pools.iter_mut().enumerate() {
         pool.id = Some(idx as u64);
 
@@ -144,74 +225,158 @@ async fn get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
             pool.farming = Some(false);
         }
 
-        let mut symbols: Vec<String> = Vec::new();
+        let symbols: Vec<String> = pool
+            .token_account_ids
+            .iter()
+            .filter_map(|token| all_metadata.get(token))
+            .map(|metadata| metadata.symbol.clone())
+            .collect();
 
-        for token in pool.token_account_ids.iter() {
-            if token_metadata.contains_key(token) {
-                let metadata = token_metadata.get(token).unwrap();
-                let symbol: String = metadata.symbol.clone();
-                symbols.push(symbol);
-            } else {
-                let vec = &vec![token.clone()];
-                let metadata = internal_update_token_metadata(vec)
-                    .await
-                    .unwrap_or_default();
+        let _ = pool.token_symbols.insert(symbols);
+    }
 
-                if !metadata.is_empty() {
-                    let symbol = metadata.get(token).unwrap().symbol.clone();
-                    symbols.push(symbol);
+    // Only whitelisted tokens are priced
+    let token_metadata: BTreeMap<String, FungibleTokenMetadata> = all_metadata
+        .into_iter()
+        .filter(|(token, _)| whitelisted_tokens.contains(token))
+        .collect();
 
-                    // redis_update_tokens_metadata(Some(metadata)); // preserve updated value
-                }
-            }
-        }
+    let prices = get_token_prices(&pools, &token_metadata);
 
-        let _ = pool.token_symbols.insert(symbols);
+    for pool in pools.iter_mut() {
+        pool.tvl = get_pool_tvl(pool, &prices);
     }
 
-    Ok(pools)
+    Ok((pools, prices))
 }
 
-pub async fn internal_farm_seeds() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let farms = get_farms().await.unwrap_or_default();
+pub async fn internal_farm_seeds(
+    block: &BlockInfo,
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<Vec<String>> {
+    let farms = get_farms(block, failures).await?;
     let mut seeds: Vec<String> = Vec::new();
 
+    // A farm with unreadable rewards is left out, the other seeds still count
     for farm in farms.iter() {
-        let status = &farm.farm_status;
-        let total_reward: u128 = farm.total_reward.parse::<u128>().unwrap();
-        let claimed_reward: u128 = farm.claimed_reward.parse::<u128>().unwrap();
-        let unclaimed_reward: u128 = farm.unclaimed_reward.parse::<u128>().unwrap();
-
-        if *status == "Running".to_string() && total_reward > claimed_reward + unclaimed_reward {
-            seeds.push(farm.seed_id.clone());
+        match has_rewards_left(farm) {
+            Ok(true) => seeds.push(farm.seed_id.clone()),
+            Ok(false) => {}
+            Err(err) => {
+                println!("ERR_PARSING_FARM_REWARDS of {}: {}", farm.farm_id, err);
+                failures.push(FetchFailure::new(
+                    Methods::ListFarmsBySeeds,
+                    &farm.farm_id,
+                    &err,
+                ));
+            }
         }
     }
 
     Ok(seeds)
 }
 
+fn has_rewards_left(farm: &FarmInfo) -> IndexerResult<bool> {
+    let total_reward: u128 = parse_amount(&farm.total_reward)?;
+    let claimed_reward: u128 = parse_amount(&farm.claimed_reward)?;
+    let unclaimed_reward: u128 = parse_amount(&farm.unclaimed_reward)?;
+
+    let distributed = claimed_reward
+        .checked_add(unclaimed_reward)
+        .ok_or_else(|| IndexerError::Decode(format!("rewards of {} overflow", farm.farm_id)))?;
+
+    Ok(farm.farm_status == "Running" && total_reward > distributed)
+}
+
+async fn fetch_token_metadata(
+    block: &BlockInfo,
+    token_contract: &str,
+) -> IndexerResult<Option<FungibleTokenMetadata>> {
+    let args = FunctionArgs::from(json!({}).to_string().into_bytes());
+    let response = call_view(block, token_contract, Methods::FtMetadata.value(), args).await?;
+
+    let mut metadata = None;
+    if let QueryResponseKind::CallResult(result) = response.kind {
+        metadata = Some(from_slice::<FungibleTokenMetadata>(&result.result)?);
+    }
+
+    Ok(metadata)
+}
+
+// Calls `ft_metadata` once per distinct token, at most `RPC_CONCURRENCY` at a time. Tokens that
+// fail are recorded in `failures` and left out
 pub async fn internal_update_token_metadata(
-    tokens: &Vec<String>,
-) -> Result<BTreeMap<String, FungibleTokenMetadata>, Box<dyn std::error::Error>> {
-    let mut valid_tokens: BTreeMap<String, FungibleTokenMetadata> = BTreeMap::new();
+    block: &BlockInfo,
+    tokens: &[String],
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+    let unique_tokens: BTreeSet<String> = tokens.iter().cloned().collect();
+
+    let results: Vec<(String, IndexerResult<Option<FungibleTokenMetadata>>)> =
+        stream::iter(unique_tokens)
+            .map(|token_contract| async move {
+                let metadata = fetch_token_metadata(block, &token_contract).await;
+                (token_contract, metadata)
+            })
+            .buffer_unordered(rpc().concurrency)
+            .collect()
+            .await;
 
-    for token_contract in tokens.iter() {
-        let args = FunctionArgs::from(json!({}).to_string().into_bytes());
-        let response = call_view(token_contract, Methods::FtMetadata.value(), args).await?;
+    let mut valid_tokens: BTreeMap<String, FungibleTokenMetadata> = BTreeMap::new();
 
-        if let QueryResponseKind::CallResult(result) = response.kind {
-            let metadata = from_slice::<FungibleTokenMetadata>(&result.result)?;
-            valid_tokens.insert(token_contract.clone(), metadata);
+    // A broken token contract must not hide the metadata of the others
+    for (token_contract, result) in results {
+        match result {
+            Ok(Some(metadata)) => {
+                valid_tokens.insert(token_contract, metadata);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("ERR_FETCHING_TOKEN_METADATA of {}: {}", token_contract, err);
+                failures.push(FetchFailure::new(
+                    Methods::FtMetadata,
+                    &token_contract,
+                    &err,
+                ));
+            }
         }
     }
 
     Ok(valid_tokens)
 }
 
-pub async fn get_whitelisted_tokens(
-) -> Result<BTreeMap<String, FungibleTokenMetadata>, Box<dyn std::error::Error>> {
+// Metadata for every token, taken from `cached` or from `fetched` when present and fetched
+// otherwise
+pub async fn get_token_metadata(
+    block: &BlockInfo,
+    tokens: &[String],
+    cached: &BTreeMap<String, FungibleTokenMetadata>,
+    fetched: &TokenMetadataCache,
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+    let mut metadata: BTreeMap<String, FungibleTokenMetadata> = BTreeMap::new();
+    let mut missing: Vec<String> = Vec::new();
+
+    for token in tokens.iter() {
+        match cached.get(token).cloned().or_else(|| fetched.get(token)) {
+            Some(known) => {
+                metadata.insert(token.clone(), known);
+            }
+            None => missing.push(token.clone()),
+        }
+    }
+
+    let missing_metadata = internal_update_token_metadata(block, &missing, failures).await?;
+    fetched.insert(&missing_metadata);
+    metadata.extend(missing_metadata);
+
+    Ok(metadata)
+}
+
+async fn get_whitelisted_token_ids(block: &BlockInfo) -> IndexerResult<Vec<String>> {
     let args = FunctionArgs::from(json!({}).to_string().into_bytes());
     let response = call_view(
+        block,
         Contracts::RefExchange.value(),
         Methods::WhitelistedTokens.value(),
         args,
@@ -224,27 +389,109 @@ pub async fn get_whitelisted_tokens(
         tokens = from_slice::<Vec<String>>(&result.result)?;
     }
 
-    let valid_tokens = internal_update_token_metadata(&tokens)
-        .await
-        .unwrap_or_default();
+    Ok(tokens)
+}
 
-    Ok(valid_tokens)
+pub async fn get_whitelisted_tokens(
+    block: &BlockInfo,
+    failures: &mut Vec<FetchFailure>,
+) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+    let tokens = get_whitelisted_token_ids(block).await?;
+
+    internal_update_token_metadata(block, &tokens, failures).await
 }
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build().mount(
-        "/",
-        routes![
-            routes::root,
-            routes::init_redis,
-            routes::init_mongo,
-            routes::list_farms,
-            routes::list_pools,
-            routes::list_whitelisted_tokens,
-            routes::mongo_list_farms,
-            routes::mongo_list_pools,
-            routes::mongo_list_whitelisted_tokens,
-        ],
-    )
+    let network_config = NetworkConfig::from_env().expect("invalid network configuration");
+    let network = init_network(network_config);
+    println!(
+        "Indexing {} via {} (exchange: {}, farm: {})",
+        network.name, network.rpc_url, network.exchange_contract, network.farm_contract
+    );
+
+    let rpc = init_rpc().expect("invalid rpc configuration");
+    init_recorder(rpc).expect("invalid RPC fixture");
+    println!(
+        "Running up to {} RPC calls at once against {}",
+        rpc.concurrency,
+        rpc.endpoints.join(", ")
+    );
+
+    let storage_config = StorageConfig::from_env().expect("invalid storage configuration");
+    let redis = storage_config.redis.clone().map(|config| {
+        println!("Using Redis at {}", config.redacted_url());
+        Arc::new(RedisPool::new(config).expect("invalid redis configuration"))
+    });
+    let mongo = storage_config.mongo.clone().map(|config| {
+        println!("Using Mongo at {:?}", config);
+        Arc::new(MongoPool::new(config))
+    });
+    let store = build_store(&storage_config, redis.clone(), mongo.clone())
+        .expect("invalid storage configuration");
+    println!("Storing data in {}", store.name());
+
+    let scheduler_config = SchedulerConfig::from_env().expect("invalid scheduler configuration");
+    let health_config = HealthConfig::from_env().expect("invalid health configuration");
+
+    build_rocket(store, redis, mongo, scheduler_config, health_config)
+}
+
+// Everything but reading the configuration, so tests can serve their own store
+fn build_rocket(
+    store: Arc<dyn Store>,
+    redis: Option<Arc<RedisPool>>,
+    mongo: Option<Arc<MongoPool>>,
+    scheduler_config: SchedulerConfig,
+    health_config: HealthConfig,
+) -> Rocket<Build> {
+    let feed = Arc::new(UpdateFeed::new());
+    let metadata = Arc::new(TokenMetadataCache::new(scheduler_config.metadata_ttl));
+    let scheduler = Scheduler::new(
+        scheduler_config,
+        store.clone(),
+        feed.clone(),
+        metadata.clone(),
+    );
+
+    // Managed even when absent, the health check takes them as `Option`
+    rocket::build()
+        .manage(redis)
+        .manage(mongo)
+        .manage(store)
+        .manage(Arc::new(scheduler))
+        .manage(feed)
+        .manage(metadata)
+        .manage(health_config)
+        .attach(HttpMetrics)
+        .attach(store_fairing())
+        .attach(Scheduler::fairing())
+        .register("/", catchers![default_catcher])
+        .mount(
+            "/",
+            routes![
+                routes::root,
+                routes::health_live,
+                routes::health_ready,
+                routes::block,
+                routes::scheduler_status,
+                routes::fetch_failures,
+                routes::rpc_health,
+                routes::prometheus_metrics,
+                routes::init,
+                routes::list_farms,
+                routes::list_pools,
+                routes::tvl,
+                routes::quote,
+                routes::route,
+                routes::get_pool,
+                routes::get_farm,
+                routes::get_token,
+                routes::pool_history,
+                routes::farm_history,
+                routes::token_history,
+                routes::list_whitelisted_tokens,
+                routes::events,
+            ],
+        )
 }
@@ -0,0 +1,52 @@
+use crate::*;
+
+use std::time::{Duration, Instant};
+
+/***************************
+    Token metadata cache
+****************************/
+
+// Metadata fetched during pool refreshes. Only whitelisted metadata is stored, so this is what
+// keeps every refresh from fetching the other pool tokens again. Entries older than `ttl` are
+// fetched again, a `ttl` of zero disables the cache
+pub struct TokenMetadataCache {
+    ttl: Duration,
+    entries: Mutex<BTreeMap<String, (Instant, FungibleTokenMetadata)>>,
+}
+
+impl TokenMetadataCache {
+    pub fn new(ttl: Duration) -> TokenMetadataCache {
+        TokenMetadataCache {
+            ttl,
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn get(&self, token: &str) -> Option<FungibleTokenMetadata> {
+        let entries = self.entries.lock().unwrap();
+        let (fetched_at, metadata) = entries.get(token)?;
+
+        if fetched_at.elapsed() >= self.ttl {
+            return None;
+        }
+
+        Some(metadata.clone())
+    }
+
+    // Expired entries are dropped here, so the cache never holds more than the tokens fetched
+    // within one `ttl`
+    pub fn insert(&self, fetched: &BTreeMap<String, FungibleTokenMetadata>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+
+        for (token, metadata) in fetched.iter() {
+            entries.insert(token.clone(), (now, metadata.clone()));
+        }
+    }
+}
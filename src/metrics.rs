@@ -0,0 +1,180 @@
+use crate::*;
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+/***************************
+    Prometheus metrics
+****************************/
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// Refreshes take seconds to minutes
+const REFRESH_BUCKETS: [f64; 9] = [0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+pub struct Metrics {
+    registry: Registry,
+    /// View calls by method (`Methods` value, or `block`) and outcome.
+    pub rpc_calls: IntCounterVec,
+    pub rpc_latency: HistogramVec,
+    /// Calls held back by an endpoint's rate limiter.
+    pub rpc_throttled: IntCounterVec,
+    /// Refreshes by dataset and outcome.
+    pub refreshes: IntCounterVec,
+    pub refresh_duration: HistogramVec,
+    /// Items stored by the last successful refresh, by dataset.
+    pub items: IntGaugeVec,
+    /// Redis and Mongo failures, by backend.
+    pub storage_errors: IntCounterVec,
+    pub http_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some(String::from("indexer")), None)?;
+
+        let rpc_calls = IntCounterVec::new(
+            Opts::new("rpc_calls_total", "RPC calls by method and outcome"),
+            &["method", "outcome"],
+        )?;
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("rpc_call_duration_seconds", "RPC call latency by method"),
+            &["method"],
+        )?;
+        let rpc_throttled = IntCounterVec::new(
+            Opts::new(
+                "rpc_throttled_calls_total",
+                "RPC calls delayed by the rate limiter, by endpoint",
+            ),
+            &["endpoint"],
+        )?;
+        let refreshes = IntCounterVec::new(
+            Opts::new("refreshes_total", "Refreshes by dataset and outcome"),
+            &["dataset", "outcome"],
+        )?;
+        let refresh_duration = HistogramVec::new(
+            HistogramOpts::new("refresh_duration_seconds", "Refresh duration by dataset")
+                .buckets(REFRESH_BUCKETS.to_vec()),
+            &["dataset"],
+        )?;
+        let items = IntGaugeVec::new(
+            Opts::new("items", "Items stored by the last refresh, by dataset"),
+            &["dataset"],
+        )?;
+        let storage_errors = IntCounterVec::new(
+            Opts::new("storage_errors_total", "Storage errors by backend"),
+            &["backend"],
+        )?;
+        let http_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["method", "route", "status"],
+        )?;
+
+        registry.register(Box::new(rpc_calls.clone()))?;
+        registry.register(Box::new(rpc_latency.clone()))?;
+        registry.register(Box::new(rpc_throttled.clone()))?;
+        registry.register(Box::new(refreshes.clone()))?;
+        registry.register(Box::new(refresh_duration.clone()))?;
+        registry.register(Box::new(items.clone()))?;
+        registry.register(Box::new(storage_errors.clone()))?;
+        registry.register(Box::new(http_latency.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            rpc_calls,
+            rpc_latency,
+            rpc_throttled,
+            refreshes,
+            refresh_duration,
+            items,
+            storage_errors,
+            http_latency,
+        })
+    }
+
+    pub fn observe_rpc<T>(&self, method: &str, started: Instant, result: &IndexerResult<T>) {
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+
+        self.rpc_calls.with_label_values(&[method, outcome]).inc();
+        self.rpc_latency
+            .with_label_values(&[method])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn observe_refresh(&self, dataset: &Dataset, started: Instant, success: bool) {
+        let outcome = if success { "ok" } else { "error" };
+
+        self.refreshes
+            .with_label_values(&[dataset.value(), outcome])
+            .inc();
+        self.refresh_duration
+            .with_label_values(&[dataset.value()])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn set_items(&self, dataset: &Dataset, count: usize) {
+        self.items
+            .with_label_values(&[dataset.value()])
+            .set(count as i64);
+    }
+
+    pub fn encode(&self) -> IndexerResult<String> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| IndexerError::Config(format!("can't encode metrics: {}", err)))?;
+
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("invalid metrics"))
+}
+
+// Start of the request, kept in the request local cache
+struct RequestStart(Instant);
+
+// Times every request, labelled with the matched route pattern rather than the raw path
+pub struct HttpMetrics;
+
+#[rocket::async_trait]
+impl Fairing for HttpMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let started = request.local_cache(|| RequestStart(Instant::now()));
+        let route = request
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| String::from("unmatched"));
+
+        metrics()
+            .http_latency
+            .with_label_values(&[
+                request.method().as_str(),
+                &route,
+                &response.status().code.to_string(),
+            ])
+            .observe(started.0.elapsed().as_secs_f64());
+    }
+}
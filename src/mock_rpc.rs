@@ -0,0 +1,353 @@
+use crate::*;
+
+use near_primitives::serialize::from_base64;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/***************************
+    Mock NEAR JSON-RPC
+****************************/
+
+// Test support: a local stand-in for the NEAR JSON-RPC serving canned contract state, so
+// refreshes and routes run without a network
+
+// 32 zero bytes, base58 encoded
+const ZERO_HASH: &str = "11111111111111111111111111111111";
+
+/// Canned contract state served by the mock RPC, as the contracts would return it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MockRpcData {
+    pub block_height: u64,
+    pub pools: Vec<Value>,
+    pub seeds: BTreeMap<String, String>,
+    /// Farms returned by `list_farms_by_seed`, by seed id.
+    pub farms: BTreeMap<String, Vec<Value>>,
+    pub whitelisted_tokens: Vec<String>,
+    /// `ft_metadata` of each token contract, tokens missing here fail like a broken contract.
+    pub metadata: BTreeMap<String, Value>,
+}
+
+impl MockRpcData {
+    // A USDT/wNEAR simple pool, a farmed USDT/USDC stable pool and a pool holding
+    // `unlisted.testnet`, a non-whitelisted token whose `ft_metadata` fails. Account ids are the
+    // testnet ones so the default stablecoins price them
+    pub fn fixture(exchange: &str) -> MockRpcData {
+        let stable_seed = format!("{}@1", exchange);
+
+        let metadata = |name: &str, symbol: &str, decimals: u8| {
+            json!({
+                "spec": "ft-1.0.0",
+                "name": name,
+                "symbol": symbol,
+                "icon": null,
+                "reference": null,
+                "reference_hash": null,
+                "decimals": decimals,
+            })
+        };
+
+        let mut data = MockRpcData {
+            block_height: 100_000_000,
+            ..Default::default()
+        };
+
+        data.pools = vec![
+            json!({
+                "pool_kind": "SIMPLE_POOL",
+                "token_account_ids": ["usdt.fakes.testnet", "wrap.testnet"],
+                "amounts": ["500000000000", "100000000000000000000000000000"],
+                "total_fee": 30,
+                "shares_total_supply": "1000000000000000000000000",
+                "amp": 0,
+            }),
+            json!({
+                "pool_kind": "STABLE_SWAP",
+                "token_account_ids": ["usdt.fakes.testnet", "usdc.fakes.testnet"],
+                "amounts": ["1000000000000", "1000000000000"],
+                "total_fee": 5,
+                "shares_total_supply": "2000000000000000000000000",
+                "amp": 240,
+            }),
+            json!({
+                "pool_kind": "SIMPLE_POOL",
+                "token_account_ids": ["wrap.testnet", "unlisted.testnet"],
+                "amounts": ["1000000000000000000000000000", "5000000000000000000000"],
+                "total_fee": 30,
+                "shares_total_supply": "1000000000000000000000000",
+                "amp": 0,
+            }),
+        ];
+
+        data.seeds.insert(
+            stable_seed.clone(),
+            String::from("1000000000000000000000000"),
+        );
+
+        data.farms.insert(
+            stable_seed.clone(),
+            vec![json!({
+                "farm_id": format!("{}#0", stable_seed),
+                "farm_kind": "SIMPLE_FARM",
+                "farm_status": "Running",
+                "seed_id": stable_seed,
+                "reward_token": "wrap.testnet",
+                "start_at": 0,
+                "reward_per_session": "1000000000000000000000000",
+                "session_interval": 3600,
+                "total_reward": "1000000000000000000000000000",
+                "cur_round": 10,
+                "last_round": 10,
+                "claimed_reward": "5000000000000000000000000",
+                "unclaimed_reward": "5000000000000000000000000",
+                "beneficiary_reward": "0",
+            })],
+        );
+
+        data.whitelisted_tokens = vec![
+            String::from("usdt.fakes.testnet"),
+            String::from("usdc.fakes.testnet"),
+            String::from("wrap.testnet"),
+        ];
+
+        data.metadata.insert(
+            String::from("usdt.fakes.testnet"),
+            metadata("Tether USD", "USDT", 6),
+        );
+        data.metadata.insert(
+            String::from("usdc.fakes.testnet"),
+            metadata("USD Coin", "USDC", 6),
+        );
+        data.metadata.insert(
+            String::from("wrap.testnet"),
+            metadata("Wrapped NEAR", "wNEAR", 24),
+        );
+
+        data
+    }
+
+    fn block(&self) -> Value {
+        let timestamp = now_secs() * 1_000_000_000;
+
+        json!({
+            "author": "test.near",
+            "header": {
+                "height": self.block_height,
+                "prev_height": self.block_height.saturating_sub(1),
+                "epoch_id": ZERO_HASH,
+                "next_epoch_id": ZERO_HASH,
+                "hash": ZERO_HASH,
+                "prev_hash": ZERO_HASH,
+                "prev_state_root": ZERO_HASH,
+                "chunk_receipts_root": ZERO_HASH,
+                "chunk_headers_root": ZERO_HASH,
+                "chunk_tx_root": ZERO_HASH,
+                "outcome_root": ZERO_HASH,
+                "chunks_included": 0,
+                "challenges_root": ZERO_HASH,
+                "timestamp": timestamp,
+                "timestamp_nanosec": timestamp.to_string(),
+                "random_value": ZERO_HASH,
+                "validator_proposals": [],
+                "chunk_mask": [],
+                "gas_price": "100000000",
+                "block_ordinal": null,
+                "rent_paid": "0",
+                "validator_reward": "0",
+                "total_supply": "0",
+                "challenges_result": [],
+                "last_final_block": ZERO_HASH,
+                "last_ds_final_block": ZERO_HASH,
+                "next_bp_hash": ZERO_HASH,
+                "block_merkle_root": ZERO_HASH,
+                "epoch_sync_data_hash": null,
+                "approvals": [],
+                "signature": format!("ed25519:{}", ZERO_HASH.repeat(2)),
+                "latest_protocol_version": 52,
+            },
+            "chunks": [],
+        })
+    }
+
+    // Result of a view call, `Err` carries the message of a failed contract execution
+    fn call_function(
+        &self,
+        account_id: &str,
+        method_name: &str,
+        args: &Value,
+    ) -> Result<Value, String> {
+        let index =
+            |key: &str| args.get(key).and_then(|value| value.as_u64()).unwrap_or(0) as usize;
+
+        match method_name {
+            "get_number_of_pools" => Ok(json!(self.pools.len())),
+            "get_pools" => {
+                let from = index("from_index").min(self.pools.len());
+                let to = (from + index("limit")).min(self.pools.len());
+                Ok(json!(self.pools[from..to]))
+            }
+            "get_whitelisted_tokens" => Ok(json!(self.whitelisted_tokens)),
+            "list_seeds" => Ok(json!(self.seeds)),
+            "list_farms_by_seed" => {
+                let seed_id = args
+                    .get("seed_id")
+                    .and_then(|value| value.as_str())
+                    .unwrap_or("");
+                Ok(json!(self.farms.get(seed_id).cloned().unwrap_or_default()))
+            }
+            "ft_metadata" => self
+                .metadata
+                .get(account_id)
+                .cloned()
+                .ok_or_else(|| format!("{} has no ft_metadata", account_id)),
+            _ => Err(format!("MethodNotFound: {}", method_name)),
+        }
+    }
+
+    fn query(&self, params: &Value) -> Result<Value, String> {
+        let field = |key: &str| {
+            params
+                .get(key)
+                .and_then(|value| value.as_str())
+                .unwrap_or("")
+        };
+
+        if field("request_type") != "call_function" {
+            return Err(format!(
+                "unsupported request_type {}",
+                field("request_type")
+            ));
+        }
+
+        let args = from_base64(field("args_base64")).map_err(|err| err.to_string())?;
+        let args: Value = serde_json::from_slice(&args).unwrap_or(Value::Null);
+
+        let result = self.call_function(field("account_id"), field("method_name"), &args)?;
+
+        Ok(json!({
+            "result": serde_json::to_vec(&result).unwrap_or_default(),
+            "logs": [],
+            "block_height": self.block_height,
+            "block_hash": ZERO_HASH,
+        }))
+    }
+
+    // JSON-RPC response to one request
+    fn respond(&self, request: &Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = match request.get("method").and_then(|value| value.as_str()) {
+            Some("block") => Ok(self.block()),
+            Some("query") => self.query(&params),
+            method => Err(format!("unsupported method {:?}", method)),
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(message) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "name": "HANDLER_ERROR",
+                    "cause": {
+                        "name": "CONTRACT_EXECUTION_ERROR",
+                        "info": {
+                            "vm_error": message,
+                            "block_height": self.block_height,
+                            "block_hash": ZERO_HASH,
+                        },
+                    },
+                    "code": -32000,
+                    "message": "Server error",
+                    "data": message,
+                },
+            }),
+        }
+    }
+}
+
+// Reads one HTTP request and answers it, the connection is closed afterwards
+async fn serve_connection(data: Arc<MockRpcData>, mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let (header_end, content_length) = loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&buffer[..end]).to_lowercase();
+            let content_length = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (end + 4, content_length);
+        }
+    };
+
+    while buffer.len() < header_end + content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let body = &buffer[header_end..(header_end + content_length).min(buffer.len())];
+    let response = match serde_json::from_slice::<Value>(body) {
+        Ok(request) => data.respond(&request),
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32700, "message": "Parse error", "data": err.to_string() },
+        }),
+    };
+
+    let payload = response.to_string();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        payload.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(payload.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Serves `data` on 127.0.0.1:`port` (0 picks a free port) and returns the URL to point the RPC
+// client at. The server lives as long as the Tokio runtime it was started from
+pub fn start_mock_rpc(data: MockRpcData, port: u16) -> IndexerResult<String> {
+    let bind_error =
+        |err: std::io::Error| IndexerError::Config(format!("can't start the mock RPC: {}", err));
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port)).map_err(bind_error)?;
+    listener.set_nonblocking(true).map_err(bind_error)?;
+    let listener = TcpListener::from_std(listener).map_err(bind_error)?;
+    let address = listener.local_addr().map_err(bind_error)?;
+
+    let data = Arc::new(data);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let data = data.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve_connection(data, stream).await {
+                            println!("ERR_MOCK_RPC: {}", err);
+                        }
+                    });
+                }
+                Err(err) => println!("ERR_MOCK_RPC: {}", err),
+            }
+        }
+    });
+
+    Ok(format!("http://{}", address))
+}
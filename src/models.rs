@@ -1,13 +1,15 @@
 use crate::*;
 
-#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone, PartialEq)]
 pub struct TokenInfo {
+    pub account_id: String,
     pub decimal: u8,
+    /// USD price derived from pool reserves.
     pub price: String,
     pub symbol: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone, PartialEq)]
 pub struct FarmInfo {
     pub farm_id: String,
     pub farm_kind: String,
@@ -23,9 +25,25 @@ pub struct FarmInfo {
     pub claimed_reward: String,
     pub unclaimed_reward: String,
     pub beneficiary_reward: String,
+    /// Total amount staked in the farm seed.
+    pub seed_amount: Option<String>,
+    /// Annualized reward rate in percent of the staked value.
+    pub apr: Option<f64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+impl FarmInfo {
+    // Compares what the contract returned, `apr` moves with prices
+    pub fn same_state(&self, other: &FarmInfo) -> bool {
+        let without_apr = |farm: &FarmInfo| FarmInfo {
+            apr: None,
+            ..farm.clone()
+        };
+
+        without_apr(self) == without_apr(other)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone, PartialEq)]
 pub struct PoolInfo {
     pub id: Option<u64>,
     /// Pool kind.
@@ -41,6 +59,22 @@ pub struct PoolInfo {
     pub amp: u64,
     pub farming: Option<bool>,
     pub token_symbols: Option<Vec<String>>,
+    /// Total value locked in USD.
+    pub tvl: Option<f64>,
+}
+
+impl PoolInfo {
+    // Compares what the contract returned. `tvl` moves with prices, and `farming` and
+    // `token_symbols` come from other datasets
+    pub fn same_state(&self, other: &PoolInfo) -> bool {
+        self.id == other.id
+            && self.pool_kind == other.pool_kind
+            && self.token_account_ids == other.token_account_ids
+            && self.amounts == other.amounts
+            && self.total_fee == other.total_fee
+            && self.shares_total_supply == other.shares_total_supply
+            && self.amp == other.amp
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
@@ -52,4 +86,186 @@ pub struct FungibleTokenMetadata {
     pub reference: Option<String>,
     pub reference_hash: Option<String>,
     pub decimals: u8,
+    /// USD price, filled in from the latest pool refresh.
+    #[serde(default)]
+    pub price: Option<String>,
+}
+
+/// Expected output of a swap against the cached pool state.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SwapQuote {
+    pub pool_id: u64,
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_in: String,
+    pub amount_out: String,
+    /// Fraction of the output lost to slippage, fees excluded.
+    pub price_impact: f64,
+}
+
+/// Best path found between two tokens across the cached pools.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SwapRoute {
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_in: String,
+    pub amount_out: String,
+    pub pool_ids: Vec<u64>,
+    /// One quote per pool, in swap order.
+    pub hops: Vec<SwapQuote>,
+    /// Combined price impact of all hops.
+    pub price_impact: f64,
+}
+
+/// Protocol-wide TVL in USD.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProtocolTvl {
+    pub total: f64,
+    pub by_pool_kind: BTreeMap<String, f64>,
+    pub by_token: BTreeMap<String, f64>,
+}
+
+/// Token metadata as stored in Mongo, keyed by the token contract.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenRecord {
+    pub account_id: String,
+    #[serde(flatten)]
+    pub metadata: FungibleTokenMetadata,
+}
+
+/// Block every view call of a refresh is pinned to.
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+pub struct BlockInfo {
+    pub height: u64,
+    pub hash: String,
+    /// Block timestamp in nanoseconds.
+    pub timestamp: u64,
+}
+
+impl BlockInfo {
+    pub fn timestamp_secs(&self) -> u64 {
+        self.timestamp / 1_000_000_000
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DatasetBlock {
+    pub dataset: String,
+    #[serde(flatten)]
+    pub block: BlockInfo,
+}
+
+/// Outcome of the scheduled refreshes of one dataset.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RefreshStatus {
+    pub interval_secs: u64,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    /// Ticks dropped because the previous refresh was still running.
+    pub skipped: u64,
+    pub last_started_at: Option<u64>,
+    pub last_finished_at: Option<u64>,
+    pub last_success_at: Option<u64>,
+    pub last_duration_ms: Option<u64>,
+    pub last_block: Option<BlockInfo>,
+    pub last_error: Option<String>,
+    /// Items left out by the last successful refresh, listed by `/failures`.
+    pub fetch_failures: u64,
+}
+
+/// Pools or farms changed by one refresh, pushed on `/events`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateEvent {
+    pub dataset: String,
+    pub block: BlockInfo,
+    pub pools: Vec<PoolInfo>,
+    pub farms: Vec<FarmInfo>,
+    /// Ids of the pools or farms gone since the previous refresh.
+    pub removed: Vec<String>,
+}
+
+/// Result of one readiness check.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HealthCheck {
+    pub ok: bool,
+    pub latency_ms: Option<u64>,
+    /// Seconds since the last successful refresh, for freshness checks.
+    pub age_secs: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Body of `/health/ready`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<String, HealthCheck>,
+}
+
+/// Health of one RPC endpoint, as tracked by the RPC pool.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EndpointHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub calls: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    pub last_failure_at: Option<u64>,
+    /// Until then the endpoint is only tried after the healthy ones.
+    pub unhealthy_until: Option<u64>,
+    /// Requests per second allowed by the rate limiter, zero is unlimited.
+    pub rate_limit: f64,
+    /// Calls that had to wait for the rate limiter, and how long they waited in total.
+    pub throttled_calls: u64,
+    pub throttled_wait_ms: u64,
+}
+
+/// A single item (token, seed) that could not be fetched during a refresh.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FetchFailure {
+    /// View method that failed.
+    pub method: String,
+    /// Token contract or seed id.
+    pub item: String,
+    pub error: String,
+    pub timestamp: u64,
+}
+
+impl FetchFailure {
+    pub fn new(method: Methods, item: &str, error: &IndexerError) -> FetchFailure {
+        FetchFailure {
+            method: method.value(),
+            item: item.to_string(),
+            error: error.to_string(),
+            timestamp: now_secs(),
+        }
+    }
+}
+
+/// State of a pool as of one refresh.
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+pub struct PoolSnapshot {
+    /// Block timestamp in seconds.
+    pub timestamp: u64,
+    pub block: BlockInfo,
+    pub pool: PoolInfo,
+}
+
+/// State of a farm as of one refresh.
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+pub struct FarmSnapshot {
+    /// Block timestamp in seconds.
+    pub timestamp: u64,
+    pub block: BlockInfo,
+    pub farm: FarmInfo,
+}
+
+/// Price of a token as of one refresh.
+#[derive(Debug, Serialize, Deserialize, RedisJsonValue, Clone)]
+pub struct TokenSnapshot {
+    /// Block timestamp in seconds.
+    pub timestamp: u64,
+    pub block: BlockInfo,
+    pub token: TokenInfo,
 }
@@ -3,141 +3,707 @@ use crate::*;
 // This trait is required to use `try_next()` on the cursor
 use futures::stream::TryStreamExt;
 
-use mongodb::{options::ClientOptions, Client};
+use futures::future::try_join_all;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{ClientOptions, Credential, Tls, TlsOptions};
+use mongodb::options::{
+    CreateCollectionOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions,
+    TimeseriesOptions,
+};
+use mongodb::{Client, Collection, Database, IndexModel};
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+// One client shared by every request. It is built on first use (parsing the URI may need a DNS
+// lookup) and keeps its own connection pool, reconnecting as needed.
+pub struct MongoPool {
+    config: MongoConfig,
+    client: OnceCell<Client>,
+}
 
-pub async fn mongo_connection() -> Result<Client, Box<dyn std::error::Error>> {
-    // Parse a connection string into an options struct.
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
+impl MongoPool {
+    pub fn new(config: MongoConfig) -> MongoPool {
+        MongoPool {
+            config,
+            client: OnceCell::new(),
+        }
+    }
 
-    // Manually set an option.
-    client_options.app_name = Some("My App".to_string());
+    pub fn collections(&self) -> &MongoCollections {
+        &self.config.collections
+    }
 
-    // Get a handle to the deployment.
-    let client = Client::with_options(client_options)?;
+    async fn connect(&self) -> IndexerResult<Client> {
+        println!(
+            "Connecting to {}/{}",
+            self.config.redacted_uri(),
+            self.config.database
+        );
+
+        // Parse a connection string into an options struct.
+        let mut client_options = ClientOptions::parse(&self.config.uri).await?;
+
+        client_options.app_name = Some(self.config.app_name.clone());
+
+        if self.config.tls {
+            let tls_options = TlsOptions::builder()
+                .ca_file_path(self.config.tls_ca_file.as_ref().map(PathBuf::from))
+                .build();
+            client_options.tls = Some(Tls::Enabled(tls_options));
+        }
+
+        if let Some(username) = &self.config.username {
+            let credential = Credential::builder()
+                .username(username.clone())
+                .password(self.config.password.clone())
+                .source(self.config.auth_source.clone())
+                .build();
+            client_options.credential = Some(credential);
+        }
+
+        // Get a handle to the deployment.
+        Ok(Client::with_options(client_options)?)
+    }
 
-    Ok(client)
-}
+    pub async fn database(&self) -> IndexerResult<Database> {
+        let client = self.client.get_or_try_init(|| self.connect()).await?;
 
-pub async fn mongo_add_tokens() -> Result<String, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
+        // Get a handle to a database.
+        Ok(client.database(&self.config.database))
+    }
 
-    // Get a handle to a database.
-    let db = client.database("db");
+    pub async fn ping(&self) -> IndexerResult<()> {
+        self.database()
+            .await?
+            .run_command(doc! { "ping": 1 }, None)
+            .await?;
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<FungibleTokenMetadata>("ft_metadata");
+        Ok(())
+    }
+}
 
-    let tokens_metadata = get_whitelisted_tokens().await.unwrap_or_default();
+// Replaces the document matching each filter, inserting it when missing, so repeated refreshes
+// keep a single document per natural id
+async fn mongo_upsert_many<T>(
+    collection: &Collection<T>,
+    records: Vec<(Document, T)>,
+) -> IndexerResult<()>
+where
+    T: Serialize + Send + Sync,
+{
+    let options = ReplaceOptions::builder().upsert(true).build();
+
+    try_join_all(
+        records
+            .into_iter()
+            .map(|(filter, record)| collection.replace_one(filter, record, options.clone())),
+    )
+    .await?;
+
+    Ok(())
+}
 
-    let mut tokens: Vec<FungibleTokenMetadata> = Vec::new();
+async fn mongo_unique_index(db: &Database, collection: &str, field: &str) -> IndexerResult<()> {
+    let options = IndexOptions::builder().unique(true).build();
+    let index = IndexModel::builder()
+        .keys(doc! { field: 1 })
+        .options(options)
+        .build();
 
-    for (_, metadata) in tokens_metadata {
-        tokens.push(metadata);
-    }
+    db.collection::<Document>(collection)
+        .create_index(index, None)
+        .await?;
 
-    // Insert
-    typed_collection.insert_many(tokens, None).await?;
+    Ok(())
+}
 
-    Ok(format!("Done"))
+async fn mongo_update_block(
+    db: &Database,
+    name: &str,
+    dataset: &Dataset,
+    block: &BlockInfo,
+) -> IndexerResult<()> {
+    let typed_collection = db.collection::<DatasetBlock>(name);
+
+    let record = DatasetBlock {
+        dataset: dataset.value().to_string(),
+        block: block.clone(),
+    };
+
+    // One document per dataset, pointing at the block of its latest refresh
+    mongo_upsert_many(
+        &typed_collection,
+        vec![(doc! { "dataset": dataset.value() }, record)],
+    )
+    .await
 }
 
-pub async fn mongo_add_farms() -> Result<String, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
+async fn mongo_update_prices(
+    db: &Database,
+    name: &str,
+    prices: &BTreeMap<String, TokenInfo>,
+) -> IndexerResult<()> {
+    let typed_collection = db.collection::<TokenInfo>(name);
 
-    // Get a handle to a database.
-    let db = client.database("db");
+    let records = prices
+        .iter()
+        .map(|(token, info)| (doc! { "account_id": token }, info.clone()))
+        .collect();
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<FarmInfo>("farms");
+    mongo_upsert_many(&typed_collection, records).await?;
 
-    let farms = get_farms().await.unwrap_or_default();
+    // Tokens without a price anymore
+    let tokens: Vec<String> = prices.keys().cloned().collect();
+    typed_collection
+        .delete_many(doc! { "account_id": { "$nin": tokens } }, None)
+        .await?;
 
-    // Insert
-    typed_collection.insert_many(farms, None).await?;
+    Ok(())
+}
 
-    Ok(format!("Done"))
+// `from` and `to` come straight from the query string, out of range values are clamped
+fn secs_to_datetime(secs: u64) -> DateTime {
+    let secs = i64::try_from(secs).unwrap_or(i64::MAX);
+
+    DateTime::from_millis(secs.saturating_mul(1000))
+}
+
+// Snapshot documents of the time-series history collections, `id` is the series key
+#[derive(Serialize, Deserialize)]
+struct HistoryRecord<T> {
+    timestamp: DateTime,
+    id: String,
+    block: BlockInfo,
+    data: T,
 }
-pub async fn mongo_add_pools() -> Result<String, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
 
-    // Get a handle to a database.
-    let db = client.database("db");
+async fn mongo_history_collection(
+    db: &Database,
+    name: &str,
+    retention: Duration,
+) -> IndexerResult<()> {
+    let existing = db.list_collection_names(None).await?;
+
+    if existing.iter().any(|collection| collection == name) {
+        return Ok(());
+    }
+
+    let timeseries = TimeseriesOptions::builder()
+        .time_field(String::from("timestamp"))
+        .meta_field(Some(String::from("id")))
+        .build();
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<PoolInfo>("pools");
+    let expire_after = if retention.is_zero() {
+        None
+    } else {
+        Some(retention)
+    };
 
-    let pools = get_pools().await.unwrap_or_default();
+    let options = CreateCollectionOptions::builder()
+        .timeseries(timeseries)
+        .expire_after_seconds(expire_after)
+        .build();
 
-    // Insert
-    typed_collection.insert_many(pools, None).await?;
-    Ok(format!("Done"))
+    db.create_collection(name, options).await?;
+
+    Ok(())
 }
 
-pub async fn mongo_get_tokens_metadata(
-) -> Result<Vec<FungibleTokenMetadata>, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
+async fn mongo_add_history<T>(
+    db: &Database,
+    name: &str,
+    block: &BlockInfo,
+    snapshots: Vec<(String, T)>,
+) -> IndexerResult<()>
+where
+    T: Serialize + Send + Sync,
+{
+    if snapshots.is_empty() {
+        return Ok(());
+    }
 
-    // Get a handle to a database.
-    let db = client.database("db");
+    let typed_collection = db.collection::<HistoryRecord<T>>(name);
+    let timestamp = secs_to_datetime(block.timestamp_secs());
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<FungibleTokenMetadata>("ft_metadata");
+    let records = snapshots.into_iter().map(|(id, data)| HistoryRecord {
+        timestamp,
+        id,
+        block: block.clone(),
+        data,
+    });
 
-    // Query
-    let mut cursor = typed_collection.find(None, None).await?;
+    typed_collection.insert_many(records, None).await?;
 
-    let mut tokens: Vec<FungibleTokenMetadata> = Vec::new();
+    Ok(())
+}
+
+async fn mongo_get_history<T>(
+    db: &Database,
+    name: &str,
+    id: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> IndexerResult<Vec<(u64, BlockInfo, T)>>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    let typed_collection = db.collection::<HistoryRecord<T>>(name);
+
+    let snapshot = |record: HistoryRecord<T>| {
+        let timestamp = (record.timestamp.timestamp_millis() / 1000) as u64;
+        (timestamp, record.block, record.data)
+    };
+
+    let mut snapshots: Vec<(u64, BlockInfo, T)> = Vec::new();
+
+    let mut range = Document::new();
+    if let Some(from) = from {
+        // Nothing may have changed inside the range, start it from the last known state
+        let options = FindOneOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .build();
+        let latest = typed_collection
+            .find_one(
+                doc! { "id": id, "timestamp": { "$lte": secs_to_datetime(from) } },
+                options,
+            )
+            .await?;
+
+        snapshots.extend(latest.map(snapshot));
+        range.insert("$gt", secs_to_datetime(from));
+    }
+    if let Some(to) = to {
+        range.insert("$lte", secs_to_datetime(to));
+    }
+
+    let mut filter = doc! { "id": id };
+    if !range.is_empty() {
+        filter.insert("timestamp", range);
+    }
+
+    // Query
+    let options = FindOptions::builder().sort(doc! { "timestamp": 1 }).build();
+    let mut cursor = typed_collection.find(filter, options).await?;
 
     // Iterate over the results of the cursor.
-    while let Some(token) = cursor.try_next().await? {
-        println!("token name: {}", token.name);
-        tokens.push(token);
+    while let Some(record) = cursor.try_next().await? {
+        snapshots.push(snapshot(record));
     }
 
-    Ok(tokens)
+    Ok(snapshots)
 }
 
-pub async fn mongo_get_farms() -> Result<Vec<FarmInfo>, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
+pub struct MongoStore {
+    pub pool: Arc<MongoPool>,
+    pub history_retention: Duration,
+    // Set once the indexes and history collections exist
+    indexes: OnceCell<()>,
+}
 
-    // Get a handle to a database.
-    let db = client.database("db");
+impl MongoStore {
+    pub fn new(pool: Arc<MongoPool>, history_retention: Duration) -> MongoStore {
+        MongoStore {
+            pool,
+            history_retention,
+            indexes: OnceCell::new(),
+        }
+    }
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<FarmInfo>("farms");
+    // Run before every write: a time-series collection can't be created once an insert has
+    // created a plain one. A failed attempt is retried on the next write
+    async fn ensure_indexes(&self) -> IndexerResult<()> {
+        self.indexes
+            .get_or_try_init(|| async {
+                let db = self.pool.database().await?;
+                let collections = self.pool.collections();
+
+                mongo_unique_index(&db, &collections.tokens, "account_id").await?;
+                mongo_unique_index(&db, &collections.farms, "farm_id").await?;
+                mongo_unique_index(&db, &collections.pools, "id").await?;
+                mongo_unique_index(&db, &collections.prices, "account_id").await?;
+                mongo_unique_index(&db, &collections.blocks, "dataset").await?;
+
+                for name in [
+                    &collections.pool_history,
+                    &collections.farm_history,
+                    &collections.token_history,
+                ] {
+                    mongo_history_collection(&db, name, self.history_retention).await?;
+                }
+
+                Ok::<(), IndexerError>(())
+            })
+            .await?;
+
+        Ok(())
+    }
 
-    // Query
-    let mut cursor = typed_collection.find(None, None).await?;
+    async fn full_snapshot_due(&self, dataset: &Dataset, block: &BlockInfo) -> IndexerResult<bool> {
+        let blocks = self.get_blocks().await?;
+
+        Ok(full_snapshot_due(
+            blocks.get(dataset.value()),
+            block,
+            self.history_retention,
+        ))
+    }
+}
+
+#[rocket::async_trait]
+impl Store for MongoStore {
+    fn name(&self) -> String {
+        String::from("mongo")
+    }
 
-    let mut farms: Vec<FarmInfo> = Vec::new();
+    // Mongo may come up after us, indexes are then created on the first write
+    async fn init(&self) -> IndexerResult<()> {
+        if let Err(err) = self.ensure_indexes().await {
+            println!("ERR_MONGO_UNAVAILABLE: {}", err);
+        }
 
-    // Iterate over the results of the cursor.
-    while let Some(farm) = cursor.try_next().await? {
-        println!("token name: {:#?}", farm.seed_id);
-        farms.push(farm);
+        Ok(())
     }
 
-    Ok(farms)
-}
-pub async fn mongo_get_pools() -> Result<Vec<PoolInfo>, Box<dyn std::error::Error>> {
-    let client = mongo_connection().await.unwrap();
+    async fn store_tokens(
+        &self,
+        block: &BlockInfo,
+        tokens: &BTreeMap<String, FungibleTokenMetadata>,
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        self.ensure_indexes().await?;
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<TokenRecord>(&collections.tokens);
+
+        let records = tokens
+            .iter()
+            .map(|(token, metadata)| {
+                let record = TokenRecord {
+                    account_id: token.clone(),
+                    metadata: metadata.clone(),
+                };
+                (doc! { "account_id": token }, record)
+            })
+            .collect();
+
+        // Tokens that failed keep their stored metadata
+        let mut kept: BTreeSet<String> = failed_items(failures, Methods::FtMetadata);
+        kept.extend(tokens.keys().cloned());
+        let kept: Vec<String> = kept.into_iter().collect();
+
+        // Upsert
+        mongo_upsert_many(&typed_collection, records).await?;
+        typed_collection
+            .delete_many(doc! { "account_id": { "$nin": kept } }, None)
+            .await?;
+        mongo_update_block(&db, &collections.blocks, &Dataset::Tokens, block).await
+    }
 
-    // Get a handle to a database.
-    let db = client.database("db");
+    async fn store_farms(
+        &self,
+        block: &BlockInfo,
+        farms: &[FarmInfo],
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        self.ensure_indexes().await?;
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<FarmInfo>(&collections.farms);
+
+        let records = farms
+            .iter()
+            .map(|farm| (doc! { "farm_id": &farm.farm_id }, farm.clone()))
+            .collect();
+
+        let by_id = |farms: Vec<FarmInfo>| -> BTreeMap<String, FarmInfo> {
+            farms
+                .into_iter()
+                .map(|farm| (farm.farm_id.clone(), farm))
+                .collect()
+        };
+        let stored = if self.full_snapshot_due(&Dataset::Farms, block).await? {
+            BTreeMap::new()
+        } else {
+            by_id(self.get_farms().await?)
+        };
+        let snapshots = changed_entities(&stored, &by_id(farms.to_vec()), FarmInfo::same_state);
+
+        let farm_ids: Vec<String> = farms.iter().map(|farm| farm.farm_id.clone()).collect();
+        let failed_seeds: Vec<String> = failed_items(failures, Methods::ListFarmsBySeeds)
+            .into_iter()
+            .collect();
+
+        // Upsert
+        mongo_upsert_many(&typed_collection, records).await?;
+        typed_collection
+            .delete_many(
+                doc! {
+                    "farm_id": { "$nin": farm_ids },
+                    "seed_id": { "$nin": failed_seeds },
+                },
+                None,
+            )
+            .await?;
+        mongo_add_history(&db, &collections.farm_history, block, snapshots).await?;
+        mongo_update_block(&db, &collections.blocks, &Dataset::Farms, block).await
+    }
 
-    // Get a handle to a collection
-    let typed_collection = db.collection::<PoolInfo>("pools");
+    async fn store_pools(
+        &self,
+        block: &BlockInfo,
+        pools: &[PoolInfo],
+        prices: &BTreeMap<String, TokenInfo>,
+    ) -> IndexerResult<()> {
+        self.ensure_indexes().await?;
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<PoolInfo>(&collections.pools);
+
+        let records = pools
+            .iter()
+            .filter_map(|pool| {
+                let id = pool.id? as i64;
+                Some((doc! { "id": id }, pool.clone()))
+            })
+            .collect();
+
+        let by_id = |pools: Vec<PoolInfo>| -> BTreeMap<String, PoolInfo> {
+            pools
+                .into_iter()
+                .filter_map(|pool| Some((pool.id?.to_string(), pool)))
+                .collect()
+        };
+        let (stored_pools, stored_prices) =
+            if self.full_snapshot_due(&Dataset::Pools, block).await? {
+                (BTreeMap::new(), BTreeMap::new())
+            } else {
+                (by_id(self.get_pools().await?), self.get_prices().await?)
+            };
+        let pool_snapshots =
+            changed_entities(&stored_pools, &by_id(pools.to_vec()), PoolInfo::same_state);
+        let token_snapshots = changed_entities(&stored_prices, prices, TokenInfo::eq);
+
+        let pool_ids: Vec<i64> = pools
+            .iter()
+            .filter_map(|pool| Some(pool.id? as i64))
+            .collect();
+
+        // Upsert
+        mongo_upsert_many(&typed_collection, records).await?;
+        typed_collection
+            .delete_many(doc! { "id": { "$nin": pool_ids } }, None)
+            .await?;
+        mongo_update_prices(&db, &collections.prices, prices).await?;
+        mongo_add_history(&db, &collections.pool_history, block, pool_snapshots).await?;
+        mongo_add_history(&db, &collections.token_history, block, token_snapshots).await?;
+        mongo_update_block(&db, &collections.blocks, &Dataset::Pools, block).await
+    }
 
-    // Query
-    let mut cursor = typed_collection.find(None, None).await?;
+    async fn get_tokens(&self) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
 
-    let mut pools: Vec<PoolInfo> = Vec::new();
+        // Get a handle to a collection
+        let typed_collection = db.collection::<TokenRecord>(&collections.tokens);
 
-    // Iterate over the results of the cursor.
-    while let Some(pool) = cursor.try_next().await? {
-        println!("token name: {:#?}", pool.token_account_ids);
-        pools.push(pool);
+        // Query
+        let mut cursor = typed_collection.find(None, None).await?;
+
+        let mut tokens: BTreeMap<String, FungibleTokenMetadata> = BTreeMap::new();
+
+        // Iterate over the results of the cursor.
+        while let Some(record) = cursor.try_next().await? {
+            tokens.insert(record.account_id, record.metadata);
+        }
+
+        Ok(tokens)
+    }
+
+    async fn get_prices(&self) -> IndexerResult<BTreeMap<String, TokenInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<TokenInfo>(&collections.prices);
+
+        // Query
+        let mut cursor = typed_collection.find(None, None).await?;
+
+        let mut prices: BTreeMap<String, TokenInfo> = BTreeMap::new();
+
+        // Iterate over the results of the cursor.
+        while let Some(info) = cursor.try_next().await? {
+            prices.insert(info.account_id.clone(), info);
+        }
+
+        Ok(prices)
     }
 
-    Ok(pools)
+    async fn get_farms(&self) -> IndexerResult<Vec<FarmInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<FarmInfo>(&collections.farms);
+
+        // Query
+        let cursor = typed_collection.find(None, None).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn get_pools(&self) -> IndexerResult<Vec<PoolInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<PoolInfo>(&collections.pools);
+
+        // Query
+        let options = FindOptions::builder().sort(doc! { "id": 1 }).build();
+        let cursor = typed_collection.find(None, options).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn get_token(&self, account_id: &str) -> IndexerResult<Option<FungibleTokenMetadata>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<TokenRecord>(&collections.tokens);
+
+        // Query
+        let record = typed_collection
+            .find_one(doc! { "account_id": account_id }, None)
+            .await?;
+
+        Ok(record.map(|record| record.metadata))
+    }
+
+    async fn get_farm(&self, farm_id: &str) -> IndexerResult<Option<FarmInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<FarmInfo>(&collections.farms);
+
+        // Query
+        Ok(typed_collection
+            .find_one(doc! { "farm_id": farm_id }, None)
+            .await?)
+    }
+
+    async fn get_pool(&self, pool_id: u64) -> IndexerResult<Option<PoolInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<PoolInfo>(&collections.pools);
+
+        // Query
+        Ok(typed_collection
+            .find_one(doc! { "id": pool_id as i64 }, None)
+            .await?)
+    }
+
+    async fn get_blocks(&self) -> IndexerResult<BTreeMap<String, BlockInfo>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        // Get a handle to a collection
+        let typed_collection = db.collection::<DatasetBlock>(&collections.blocks);
+
+        // Query
+        let mut cursor = typed_collection.find(None, None).await?;
+
+        let mut blocks: BTreeMap<String, BlockInfo> = BTreeMap::new();
+
+        // Iterate over the results of the cursor.
+        while let Some(record) = cursor.try_next().await? {
+            blocks.insert(record.dataset, record.block);
+        }
+
+        Ok(blocks)
+    }
+
+    async fn get_pool_history(
+        &self,
+        pool_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<PoolSnapshot>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        let history: Vec<(u64, BlockInfo, PoolInfo)> = mongo_get_history(
+            &db,
+            &collections.pool_history,
+            &pool_id.to_string(),
+            from,
+            to,
+        )
+        .await?;
+
+        Ok(history
+            .into_iter()
+            .map(|(timestamp, block, pool)| PoolSnapshot {
+                timestamp,
+                block,
+                pool,
+            })
+            .collect())
+    }
+
+    async fn get_farm_history(
+        &self,
+        farm_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<FarmSnapshot>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        let history: Vec<(u64, BlockInfo, FarmInfo)> =
+            mongo_get_history(&db, &collections.farm_history, farm_id, from, to).await?;
+
+        Ok(history
+            .into_iter()
+            .map(|(timestamp, block, farm)| FarmSnapshot {
+                timestamp,
+                block,
+                farm,
+            })
+            .collect())
+    }
+
+    async fn get_token_history(
+        &self,
+        account_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<TokenSnapshot>> {
+        let db = self.pool.database().await?;
+        let collections = self.pool.collections();
+
+        let history: Vec<(u64, BlockInfo, TokenInfo)> =
+            mongo_get_history(&db, &collections.token_history, account_id, from, to).await?;
+
+        Ok(history
+            .into_iter()
+            .map(|(timestamp, block, token)| TokenSnapshot {
+                timestamp,
+                block,
+                token,
+            })
+            .collect())
+    }
 }
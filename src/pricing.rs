@@ -0,0 +1,235 @@
+use crate::*;
+
+/***************************
+    Token pricing
+****************************/
+
+// Pools holding less than this (in USD) on the priced side can't set a price
+const MIN_PRICING_LIQUIDITY: f64 = 100.0;
+
+// Raw on-chain amount scaled down by the token decimals
+pub fn to_decimal_amount(amount: &str, decimals: u8) -> Option<f64> {
+    let raw = amount.parse::<u128>().ok()?;
+
+    Some(raw as f64 / 10f64.powi(decimals as i32))
+}
+
+fn pool_reserve(
+    pool: &PoolInfo,
+    index: usize,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+) -> Option<f64> {
+    let token = pool.token_account_ids.get(index)?;
+    let metadata = token_metadata.get(token)?;
+    let amount = pool.amounts.get(index)?;
+
+    to_decimal_amount(amount, metadata.decimals)
+}
+
+// Stablecoins are anchored at $1. Every round then prices the tokens that share a pool with an
+// already priced token (wNEAR through its stable pairs first, everything else from there on),
+// using the deepest pool available for each token.
+pub fn get_token_prices(
+    pools: &[PoolInfo],
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+) -> BTreeMap<String, TokenInfo> {
+    let mut prices: HashMap<String, f64> = HashMap::new();
+
+    for stablecoin in network().stablecoins.iter() {
+        if token_metadata.contains_key(stablecoin) {
+            prices.insert(stablecoin.clone(), 1.0);
+        }
+    }
+
+    loop {
+        // token -> (price, liquidity of the pool it was priced from)
+        let mut candidates: HashMap<String, (f64, f64)> = HashMap::new();
+
+        for pool in pools.iter() {
+            for (quote_idx, quote) in pool.token_account_ids.iter().enumerate() {
+                let quote_price = match prices.get(quote) {
+                    Some(price) => *price,
+                    None => continue,
+                };
+
+                let liquidity = match pool_reserve(pool, quote_idx, token_metadata) {
+                    Some(amount) => amount * quote_price,
+                    None => continue,
+                };
+
+                if liquidity < MIN_PRICING_LIQUIDITY {
+                    continue;
+                }
+
+                for (base_idx, base) in pool.token_account_ids.iter().enumerate() {
+                    if prices.contains_key(base) {
+                        continue;
+                    }
+
+                    let base_amount = match pool_reserve(pool, base_idx, token_metadata) {
+                        Some(amount) if amount > 0.0 => amount,
+                        _ => continue,
+                    };
+
+                    // Stable pools hold pegged assets, their reserves say nothing about the rate
+                    let price = if pool.pool_kind == "STABLE_SWAP" {
+                        quote_price
+                    } else {
+                        liquidity / base_amount
+                    };
+
+                    let deeper = match candidates.get(base) {
+                        Some((_, current)) => liquidity > *current,
+                        None => true,
+                    };
+
+                    if deeper {
+                        candidates.insert(base.clone(), (price, liquidity));
+                    }
+                }
+            }
+        }
+
+        if candidates.is_empty() {
+            break;
+        }
+
+        for (token, (price, _)) in candidates {
+            prices.insert(token, price);
+        }
+    }
+
+    let mut tokens: BTreeMap<String, TokenInfo> = BTreeMap::new();
+
+    for (token, metadata) in token_metadata.iter() {
+        if let Some(price) = prices.get(token) {
+            let info = TokenInfo {
+                account_id: token.clone(),
+                decimal: metadata.decimals,
+                price: price.to_string(),
+                symbol: metadata.symbol.clone(),
+            };
+            tokens.insert(token.clone(), info);
+        }
+    }
+
+    tokens
+}
+
+// USD value of a raw token amount
+pub fn get_token_value(
+    token: &str,
+    amount: &str,
+    prices: &BTreeMap<String, TokenInfo>,
+) -> Option<f64> {
+    let info = prices.get(token)?;
+    let price = info.price.parse::<f64>().ok()?;
+
+    Some(to_decimal_amount(amount, info.decimal)? * price)
+}
+
+// Reserves of unpriced tokens are left out, a pool without any priced token has no TVL
+pub fn get_pool_tvl(pool: &PoolInfo, prices: &BTreeMap<String, TokenInfo>) -> Option<f64> {
+    let mut tvl: Option<f64> = None;
+
+    for (token, amount) in pool.token_account_ids.iter().zip(pool.amounts.iter()) {
+        if let Some(value) = get_token_value(token, amount, prices) {
+            tvl = Some(tvl.unwrap_or_default() + value);
+        }
+    }
+
+    tvl
+}
+
+pub fn get_protocol_tvl(pools: &[PoolInfo], prices: &BTreeMap<String, TokenInfo>) -> ProtocolTvl {
+    let mut total: f64 = 0.0;
+    let mut by_pool_kind: BTreeMap<String, f64> = BTreeMap::new();
+    let mut by_token: BTreeMap<String, f64> = BTreeMap::new();
+
+    for pool in pools.iter() {
+        for (token, amount) in pool.token_account_ids.iter().zip(pool.amounts.iter()) {
+            if let Some(value) = get_token_value(token, amount, prices) {
+                total += value;
+                *by_pool_kind.entry(pool.pool_kind.clone()).or_default() += value;
+                *by_token.entry(token.clone()).or_default() += value;
+            }
+        }
+    }
+
+    ProtocolTvl {
+        total,
+        by_pool_kind,
+        by_token,
+    }
+}
+
+const SECONDS_PER_YEAR: f64 = 31_536_000.0;
+
+// Pool behind an LP seed (`<exchange>@<pool id>`), None for single token seeds
+pub fn seed_pool_id(seed_id: &str) -> Option<u64> {
+    let (contract, pool_id) = seed_id.split_once('@')?;
+
+    if contract != Contracts::RefExchange.value() {
+        return None;
+    }
+
+    pool_id.parse::<u64>().ok()
+}
+
+// USD value of everything staked in a seed: LP shares (`<exchange>@<pool id>`) are valued
+// against the pool TVL, single token seeds against the token price
+pub fn get_seed_value(
+    seed_id: &str,
+    seed_amount: &str,
+    pools: &HashMap<u64, &PoolInfo>,
+    prices: &BTreeMap<String, TokenInfo>,
+) -> Option<f64> {
+    if !seed_id.contains('@') {
+        return get_token_value(seed_id, seed_amount, prices);
+    }
+
+    let pool = pools.get(&seed_pool_id(seed_id)?)?;
+    let staked = seed_amount.parse::<u128>().ok()? as f64;
+    let total_shares = pool.shares_total_supply.parse::<u128>().ok()? as f64;
+
+    if total_shares == 0.0 {
+        return None;
+    }
+
+    Some(pool.tvl? * staked / total_shares)
+}
+
+pub fn get_farm_apr(
+    farm: &FarmInfo,
+    pools: &HashMap<u64, &PoolInfo>,
+    prices: &BTreeMap<String, TokenInfo>,
+) -> Option<f64> {
+    if farm.farm_status != "Running" || farm.session_interval == 0 {
+        return None;
+    }
+
+    let staked_value = get_seed_value(&farm.seed_id, farm.seed_amount.as_ref()?, pools, prices)?;
+    if staked_value <= 0.0 {
+        return None;
+    }
+
+    let reward_value = get_token_value(&farm.reward_token, &farm.reward_per_session, prices)?;
+    let sessions_per_year = SECONDS_PER_YEAR / farm.session_interval as f64;
+
+    Some(reward_value * sessions_per_year / staked_value * 100.0)
+}
+
+pub fn set_farm_aprs(
+    farms: &mut [FarmInfo],
+    pools: &[PoolInfo],
+    prices: &BTreeMap<String, TokenInfo>,
+) {
+    let pools_by_id: HashMap<u64, &PoolInfo> = pools
+        .iter()
+        .filter_map(|pool| pool.id.map(|id| (id, pool)))
+        .collect();
+
+    for farm in farms.iter_mut() {
+        farm.apr = get_farm_apr(farm, &pools_by_id, prices);
+    }
+}
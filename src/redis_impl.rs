@@ -1,137 +1,504 @@
 use crate::*;
 
-use std::env;
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+use tokio::sync::OnceCell;
 
 /***************************
     Redis implementation
 ****************************/
 
-// Connect to redis
-pub fn connect() -> redis::Connection {
-    //format - host:port
-    let redis_host_name =
-        env::var("REDIS_HOSTNAME").expect("missing environment variable REDIS_HOSTNAME");
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or_default();
-
-    //if Redis server needs secure connection
-    let uri_scheme = match env::var("IS_TLS") {
-        Ok(_) => "rediss",
-        Err(_) => "redis",
-    };
-
-    let redis_conn_url = format!("{}://:{}@{}", uri_scheme, redis_password, redis_host_name);
-    println!("{}", redis_conn_url);
+const PREFIX: &str = "redis-driver";
 
-    redis::Client::open(redis_conn_url)
-        .expect("Invalid connection URL")
-        .get_connection()
-        .expect("failed to connect to Redis")
+// One multiplexed connection shared by every request, created on first use. The
+// manager reconnects on its own after a dropped connection, and a failed first
+// connect is retried on the next call.
+pub struct RedisPool {
+    config: RedisConfig,
+    client: redis::Client,
+    manager: OnceCell<ConnectionManager>,
 }
 
-pub async fn redis_update_farms() -> Result<String, Box<dyn std::error::Error>> {
-    let mut conn = connect();
-
-    let res = get_farms().await;
-    let farms = res.unwrap();
+impl RedisPool {
+    pub fn new(config: RedisConfig) -> IndexerResult<RedisPool> {
+        let client = redis::Client::open(config.url())?;
 
-    let mut driver: BTreeMap<String, FarmInfo> = BTreeMap::new();
+        Ok(RedisPool {
+            config,
+            client,
+            manager: OnceCell::new(),
+        })
+    }
 
-    for farm in farms.clone() {
-        driver.insert(farm.farm_id.clone(), farm);
+    pub async fn connection(&self) -> IndexerResult<ConnectionManager> {
+        let manager = self
+            .manager
+            .get_or_try_init(|| async {
+                println!("Connecting to {}", self.config.redacted_url());
+                ConnectionManager::new(self.client.clone()).await
+            })
+            .await
+            .map_err(|err| {
+                IndexerError::storage(
+                    "redis",
+                    format!("can't connect to {}: {}", self.config.redacted_url(), err),
+                )
+            })?;
+
+        Ok(manager.clone())
     }
 
-    let prefix = "redis-driver";
+    pub async fn ping(&self) -> IndexerResult<()> {
+        let mut conn = self.connection().await?;
+        let _: String = redis::cmd("PING").query_async(&mut conn).await?;
 
-    let _: () = redis::cmd("HSET")
-        .arg(format!("{}:{}", prefix, "farms"))
-        .arg(driver)
-        .query(&mut conn)
-        .expect("failed to execute HSET");
+        Ok(())
+    }
+}
 
-    Ok(format!("Done"))
+fn key(name: &str) -> String {
+    format!("{}:{}", PREFIX, name)
 }
 
-pub async fn redis_update_pools() -> Result<String, Box<dyn std::error::Error>> {
-    let mut conn = connect();
+// Writes of one refresh are queued on a MULTI/EXEC pipeline and sent together, so readers never
+// see the data of one block next to the `block` entry or prices of another
+async fn commit(conn: &mut ConnectionManager, pipe: &redis::Pipeline) -> IndexerResult<()> {
+    let _: () = pipe.query_async(conn).await?;
 
-    let res = get_pools().await;
-    let pools = res.unwrap();
+    Ok(())
+}
+
+// HSET rejects an empty field list, so empty maps are not written
+fn hset<T: redis::ToRedisArgs>(
+    pipe: &mut redis::Pipeline,
+    name: &str,
+    values: &BTreeMap<String, T>,
+) {
+    if values.is_empty() {
+        return;
+    }
 
-    let mut driver: BTreeMap<String, PoolInfo> = BTreeMap::new();
+    pipe.cmd("HSET").arg(key(name)).arg(values).ignore();
+}
 
-    for pool in pools.clone() {
-        driver.insert(pool.id.unwrap().to_string(), pool);
+// HDEL needs at least one field as well
+fn hdel(pipe: &mut redis::Pipeline, name: &str, fields: &[String]) {
+    if fields.is_empty() {
+        return;
     }
 
-    let prefix = "redis-driver";
+    pipe.cmd("HDEL").arg(key(name)).arg(fields).ignore();
+}
 
-    let _: () = redis::cmd("HSET")
-        .arg(format!("{}:{}", prefix, "pool"))
-        .arg(driver)
-        .query(&mut conn)
-        .expect("failed to execute HSET");
+async fn hkeys(conn: &mut ConnectionManager, name: &str) -> IndexerResult<Vec<String>> {
+    let fields: Vec<String> = redis::cmd("HKEYS").arg(key(name)).query_async(conn).await?;
 
-    Ok(format!("Done"))
+    Ok(fields)
 }
 
-pub async fn redis_update_tokens_metadata(
-    tokens: Option<BTreeMap<String, FungibleTokenMetadata>>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut conn = connect();
+async fn hgetall<T: redis::FromRedisValue>(
+    conn: &mut ConnectionManager,
+    name: &str,
+) -> IndexerResult<BTreeMap<String, T>> {
+    let info: BTreeMap<String, T> = redis::cmd("HGETALL")
+        .arg(key(name))
+        .query_async(conn)
+        .await?;
 
-    let tokens_metadata = if tokens.is_some() {
-        tokens.unwrap()
-    } else {
-        let res = get_whitelisted_tokens().await;
-        res.unwrap()
-    };
+    Ok(info)
+}
 
-    let prefix = "redis-driver";
+async fn hget<T: redis::FromRedisValue>(
+    conn: &mut ConnectionManager,
+    name: &str,
+    field: &str,
+) -> IndexerResult<Option<T>> {
+    let info: Option<T> = redis::cmd("HGET")
+        .arg(key(name))
+        .arg(field)
+        .query_async(conn)
+        .await?;
+
+    Ok(info)
+}
 
-    let _: () = redis::cmd("HSET")
-        .arg(format!("{}:{}", prefix, "metadata"))
-        .arg(tokens_metadata)
-        .query(&mut conn)
-        .expect("failed to execute HSET");
+fn update_block(pipe: &mut redis::Pipeline, dataset: &Dataset, block: &BlockInfo) {
+    pipe.cmd("HSET")
+        .arg(key("block"))
+        .arg(dataset.value())
+        .arg(block)
+        .ignore();
+}
 
-    Ok(format!("Done"))
+// Each changed entity gets a snapshot in a sorted set scored by block timestamp. Older members are
+// trimmed on write, and the key expires once nothing was written to it for the whole retention.
+// Entities still listed are snapshotted again before that, so only ids that are gone expire
+fn add_history<T: redis::ToRedisArgs>(
+    pipe: &mut redis::Pipeline,
+    name: &str,
+    block: &BlockInfo,
+    retention: Duration,
+    snapshots: Vec<(String, T)>,
+) {
+    let timestamp = block.timestamp_secs();
+
+    for (id, snapshot) in snapshots.iter() {
+        let history_key = key(&format!("history:{}:{}", name, id));
+
+        pipe.cmd("ZADD")
+            .arg(&history_key)
+            .arg(timestamp)
+            .arg(snapshot)
+            .ignore();
+
+        if !retention.is_zero() {
+            pipe.cmd("ZREMRANGEBYSCORE")
+                .arg(&history_key)
+                .arg("-inf")
+                .arg(format!(
+                    "({}",
+                    timestamp.saturating_sub(retention.as_secs())
+                ))
+                .ignore();
+            pipe.cmd("EXPIRE")
+                .arg(&history_key)
+                .arg(retention.as_secs())
+                .ignore();
+        }
+    }
 }
 
-pub async fn get_redis_tokens_metadata() -> BTreeMap<String, FungibleTokenMetadata> {
-    let mut conn = connect();
+async fn get_history<T: redis::FromRedisValue>(
+    conn: &mut ConnectionManager,
+    name: &str,
+    id: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> IndexerResult<Vec<T>> {
+    let history_key = key(&format!("history:{}:{}", name, id));
+    let max = to.map_or(String::from("+inf"), |to| to.to_string());
+
+    let from = match from {
+        Some(from) => from,
+        None => {
+            let snapshots: Vec<T> = redis::cmd("ZRANGEBYSCORE")
+                .arg(&history_key)
+                .arg("-inf")
+                .arg(max)
+                .query_async(conn)
+                .await?;
+
+            return Ok(snapshots);
+        }
+    };
 
-    let prefix = "redis-driver";
+    // Snapshots are only written on change, the state at `from` is the latest one up to it
+    let (mut snapshots, range): (Vec<T>, Vec<T>) = redis::pipe()
+        .cmd("ZREVRANGEBYSCORE")
+        .arg(&history_key)
+        .arg(from)
+        .arg("-inf")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(1)
+        .cmd("ZRANGEBYSCORE")
+        .arg(&history_key)
+        .arg(format!("({}", from))
+        .arg(max)
+        .query_async(conn)
+        .await?;
+
+    snapshots.extend(range);
+
+    Ok(snapshots)
+}
 
-    let info: BTreeMap<String, FungibleTokenMetadata> = redis::cmd("HGETALL")
-        .arg(format!("{}:{}", prefix, "metadata"))
-        .query(&mut conn)
-        .expect("failed to execute HGETALL");
+pub struct RedisStore {
+    pub pool: Arc<RedisPool>,
+    pub history_retention: Duration,
+}
 
-    info
+impl RedisStore {
+    async fn full_snapshot_due(
+        &self,
+        conn: &mut ConnectionManager,
+        dataset: &Dataset,
+        block: &BlockInfo,
+    ) -> IndexerResult<bool> {
+        let previous: Option<BlockInfo> = hget(conn, "block", dataset.value()).await?;
+
+        Ok(full_snapshot_due(
+            previous.as_ref(),
+            block,
+            self.history_retention,
+        ))
+    }
 }
 
-pub async fn get_redis_farms() -> BTreeMap<String, FarmInfo> {
-    let mut conn = connect();
+#[rocket::async_trait]
+impl Store for RedisStore {
+    fn name(&self) -> String {
+        String::from("redis")
+    }
 
-    let prefix = "redis-driver";
+    // Redis may come up after us, the pool retries on the next call
+    async fn init(&self) -> IndexerResult<()> {
+        if let Err(err) = self.pool.connection().await {
+            println!("ERR_REDIS_UNAVAILABLE: {}", err);
+        }
 
-    let info: BTreeMap<String, FarmInfo> = redis::cmd("HGETALL")
-        .arg(format!("{}:{}", prefix, "farms"))
-        .query(&mut conn)
-        .expect("failed to execute HGETALL");
+        Ok(())
+    }
 
-    info
-}
+    async fn store_tokens(
+        &self,
+        block: &BlockInfo,
+        tokens: &BTreeMap<String, FungibleTokenMetadata>,
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        let mut conn = self.pool.connection().await?;
+
+        let failed_tokens = failed_items(failures, Methods::FtMetadata);
+        let removed: Vec<String> = hkeys(&mut conn, "metadata")
+            .await?
+            .into_iter()
+            .filter(|token| !tokens.contains_key(token) && !failed_tokens.contains(token))
+            .collect();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        hset(&mut pipe, "metadata", tokens);
+        hdel(&mut pipe, "metadata", &removed);
+        update_block(&mut pipe, &Dataset::Tokens, block);
+        commit(&mut conn, &pipe).await
+    }
 
-pub async fn get_redis_pools() -> BTreeMap<String, PoolInfo> {
-    let mut conn = connect();
+    async fn store_farms(
+        &self,
+        block: &BlockInfo,
+        farms: &[FarmInfo],
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        let mut conn = self.pool.connection().await?;
+
+        let mut driver: BTreeMap<String, FarmInfo> = BTreeMap::new();
+
+        for farm in farms.iter() {
+            driver.insert(farm.farm_id.clone(), farm.clone());
+        }
+
+        let stored: BTreeMap<String, FarmInfo> = hgetall(&mut conn, "farms").await?;
+
+        let failed_seeds = failed_items(failures, Methods::ListFarmsBySeeds);
+        let removed = removed_ids(&stored, &driver, |farm| {
+            failed_seeds.contains(&farm.seed_id)
+        });
+
+        let full_snapshot = self
+            .full_snapshot_due(&mut conn, &Dataset::Farms, block)
+            .await?;
+        let baseline = if full_snapshot {
+            BTreeMap::new()
+        } else {
+            stored
+        };
+
+        let snapshots = changed_entities(&baseline, &driver, FarmInfo::same_state)
+            .into_iter()
+            .map(|(id, farm)| {
+                let snapshot = FarmSnapshot {
+                    timestamp: block.timestamp_secs(),
+                    block: block.clone(),
+                    farm,
+                };
+                (id, snapshot)
+            })
+            .collect();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        hset(&mut pipe, "farms", &driver);
+        hdel(&mut pipe, "farms", &removed);
+        add_history(&mut pipe, "farm", block, self.history_retention, snapshots);
+        update_block(&mut pipe, &Dataset::Farms, block);
+        commit(&mut conn, &pipe).await
+    }
 
-    let prefix = "redis-driver";
+    async fn store_pools(
+        &self,
+        block: &BlockInfo,
+        pools: &[PoolInfo],
+        prices: &BTreeMap<String, TokenInfo>,
+    ) -> IndexerResult<()> {
+        let mut conn = self.pool.connection().await?;
+
+        let mut driver: BTreeMap<String, PoolInfo> = BTreeMap::new();
+
+        for pool in pools.iter() {
+            if let Some(id) = pool.id {
+                driver.insert(id.to_string(), pool.clone());
+            }
+        }
+
+        let stored_pools: BTreeMap<String, PoolInfo> = hgetall(&mut conn, "pool").await?;
+        let stored_prices: BTreeMap<String, TokenInfo> = hgetall(&mut conn, "price").await?;
+
+        let removed_pools = removed_ids(&stored_pools, &driver, |_| false);
+        let removed_prices = removed_ids(&stored_prices, prices, |_| false);
+
+        let full_snapshot = self
+            .full_snapshot_due(&mut conn, &Dataset::Pools, block)
+            .await?;
+        let (pool_baseline, price_baseline) = if full_snapshot {
+            (BTreeMap::new(), BTreeMap::new())
+        } else {
+            (stored_pools, stored_prices)
+        };
+
+        let pool_snapshots = changed_entities(&pool_baseline, &driver, PoolInfo::same_state)
+            .into_iter()
+            .map(|(id, pool)| {
+                let snapshot = PoolSnapshot {
+                    timestamp: block.timestamp_secs(),
+                    block: block.clone(),
+                    pool,
+                };
+                (id, snapshot)
+            })
+            .collect();
+
+        let token_snapshots = changed_entities(&price_baseline, prices, TokenInfo::eq)
+            .into_iter()
+            .map(|(token, info)| {
+                let snapshot = TokenSnapshot {
+                    timestamp: block.timestamp_secs(),
+                    block: block.clone(),
+                    token: info,
+                };
+                (token, snapshot)
+            })
+            .collect();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        hset(&mut pipe, "pool", &driver);
+        hset(&mut pipe, "price", prices);
+        hdel(&mut pipe, "pool", &removed_pools);
+        hdel(&mut pipe, "price", &removed_prices);
+        add_history(
+            &mut pipe,
+            "pool",
+            block,
+            self.history_retention,
+            pool_snapshots,
+        );
+        add_history(
+            &mut pipe,
+            "token",
+            block,
+            self.history_retention,
+            token_snapshots,
+        );
+        update_block(&mut pipe, &Dataset::Pools, block);
+        commit(&mut conn, &pipe).await
+    }
 
-    let info: BTreeMap<String, PoolInfo> = redis::cmd("HGETALL")
-        .arg(format!("{}:{}", prefix, "pool"))
-        .query(&mut conn)
-        .expect("failed to execute HGETALL");
+    async fn get_tokens(&self) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+        hgetall(&mut self.pool.connection().await?, "metadata").await
+    }
 
-    info
+    async fn get_prices(&self) -> IndexerResult<BTreeMap<String, TokenInfo>> {
+        hgetall(&mut self.pool.connection().await?, "price").await
+    }
+
+    async fn get_farms(&self) -> IndexerResult<Vec<FarmInfo>> {
+        let farms: BTreeMap<String, FarmInfo> =
+            hgetall(&mut self.pool.connection().await?, "farms").await?;
+
+        Ok(farms.into_values().collect())
+    }
+
+    async fn get_pools(&self) -> IndexerResult<Vec<PoolInfo>> {
+        let pools: BTreeMap<String, PoolInfo> =
+            hgetall(&mut self.pool.connection().await?, "pool").await?;
+
+        // Hash keys sort as strings, keep the pools in id order
+        let mut pools: Vec<PoolInfo> = pools.into_values().collect();
+        pools.sort_by_key(|pool| pool.id);
+
+        Ok(pools)
+    }
+
+    async fn get_token(&self, account_id: &str) -> IndexerResult<Option<FungibleTokenMetadata>> {
+        hget(&mut self.pool.connection().await?, "metadata", account_id).await
+    }
+
+    async fn get_farm(&self, farm_id: &str) -> IndexerResult<Option<FarmInfo>> {
+        hget(&mut self.pool.connection().await?, "farms", farm_id).await
+    }
+
+    async fn get_pool(&self, pool_id: u64) -> IndexerResult<Option<PoolInfo>> {
+        hget(
+            &mut self.pool.connection().await?,
+            "pool",
+            &pool_id.to_string(),
+        )
+        .await
+    }
+
+    async fn get_blocks(&self) -> IndexerResult<BTreeMap<String, BlockInfo>> {
+        hgetall(&mut self.pool.connection().await?, "block").await
+    }
+
+    async fn get_pool_history(
+        &self,
+        pool_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<PoolSnapshot>> {
+        get_history(
+            &mut self.pool.connection().await?,
+            "pool",
+            &pool_id.to_string(),
+            from,
+            to,
+        )
+        .await
+    }
+
+    async fn get_farm_history(
+        &self,
+        farm_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<FarmSnapshot>> {
+        get_history(
+            &mut self.pool.connection().await?,
+            "farm",
+            farm_id,
+            from,
+            to,
+        )
+        .await
+    }
+
+    async fn get_token_history(
+        &self,
+        account_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<TokenSnapshot>> {
+        get_history(
+            &mut self.pool.connection().await?,
+            "token",
+            account_id,
+            from,
+            to,
+        )
+        .await
+    }
 }
@@ -0,0 +1,216 @@
+use crate::*;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+use serde_json::Value;
+
+/***************************
+    RPC record and replay
+****************************/
+
+static RECORDER: OnceLock<RpcRecorder> = OnceLock::new();
+
+/// One line of a fixture file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FixtureEntry {
+    /// Final block resolved at the start of a refresh of `dataset`.
+    Block { dataset: String, block: BlockInfo },
+    /// A view call, with its decoded JSON args and either its decoded result or its error.
+    Call {
+        block_height: u64,
+        contract: String,
+        method: String,
+        args: Value,
+        result: Option<Value>,
+        error: Option<String>,
+    },
+}
+
+type CallKey = (u64, String, String, String);
+
+fn call_key(block_height: u64, contract: &str, method: &str, args: &Value) -> CallKey {
+    (
+        block_height,
+        contract.to_string(),
+        method.to_string(),
+        args.to_string(),
+    )
+}
+
+// Function args are JSON for every method we call
+fn decode_args(args: &FunctionArgs) -> Value {
+    serde_json::from_slice(args.as_ref()).unwrap_or(Value::Null)
+}
+
+pub enum RpcRecorder {
+    Off,
+    Record(Mutex<File>),
+    Replay {
+        // The last recorded block of each dataset, a replayed refresh is pinned to the block of
+        // its dataset. Datasets refresh concurrently, so their calls are at different heights
+        blocks: BTreeMap<String, BlockInfo>,
+        calls: BTreeMap<CallKey, Result<Value, String>>,
+    },
+}
+
+impl RpcRecorder {
+    pub fn new(config: &RpcConfig) -> IndexerResult<RpcRecorder> {
+        if let Some(path) = &config.record {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| IndexerError::Config(format!("can't open {}: {}", path, err)))?;
+
+            println!("Recording RPC calls to {}", path);
+            return Ok(RpcRecorder::Record(Mutex::new(file)));
+        }
+
+        if let Some(path) = &config.replay {
+            let content = fs::read_to_string(path)
+                .map_err(|err| IndexerError::Config(format!("can't read {}: {}", path, err)))?;
+
+            let mut blocks = BTreeMap::new();
+            let mut calls = BTreeMap::new();
+
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                match serde_json::from_str::<FixtureEntry>(line)? {
+                    FixtureEntry::Block { dataset, block } => {
+                        blocks.insert(dataset, block);
+                    }
+                    FixtureEntry::Call {
+                        block_height,
+                        contract,
+                        method,
+                        args,
+                        result,
+                        error,
+                    } => {
+                        let response = match (result, error) {
+                            (_, Some(error)) => Err(error),
+                            (result, None) => Ok(result.unwrap_or(Value::Null)),
+                        };
+                        calls.insert(call_key(block_height, &contract, &method, &args), response);
+                    }
+                }
+            }
+
+            println!("Replaying {} RPC calls from {}", calls.len(), path);
+            return Ok(RpcRecorder::Replay { blocks, calls });
+        }
+
+        Ok(RpcRecorder::Off)
+    }
+
+    fn write(&self, entry: &FixtureEntry) {
+        if let RpcRecorder::Record(file) = self {
+            let line = match serde_json::to_string(entry) {
+                Ok(line) => line,
+                Err(err) => return println!("ERR_RECORDING_RPC: {}", err),
+            };
+
+            if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+                println!("ERR_RECORDING_RPC: {}", err);
+            }
+        }
+    }
+
+    pub fn record_block(&self, dataset: &Dataset, block: &BlockInfo) {
+        self.write(&FixtureEntry::Block {
+            dataset: dataset.value().to_string(),
+            block: block.clone(),
+        });
+    }
+
+    pub fn record_call(
+        &self,
+        block: &BlockInfo,
+        contract: &str,
+        method: &str,
+        args: &FunctionArgs,
+        response: &IndexerResult<RpcQueryResponse>,
+    ) {
+        if !matches!(self, RpcRecorder::Record(_)) {
+            return;
+        }
+
+        let (result, error) = match response {
+            Ok(response) => match &response.kind {
+                QueryResponseKind::CallResult(call) => (
+                    Some(serde_json::from_slice(&call.result).unwrap_or(Value::Null)),
+                    None,
+                ),
+                _ => return,
+            },
+            Err(err) => (None, Some(err.to_string())),
+        };
+
+        self.write(&FixtureEntry::Call {
+            block_height: block.height,
+            contract: contract.to_string(),
+            method: method.to_string(),
+            args: decode_args(args),
+            result,
+            error,
+        });
+    }
+
+    // `None` when not replaying, the call then goes to the network
+    pub fn replay_block(&self, dataset: &Dataset) -> Option<IndexerResult<BlockInfo>> {
+        match self {
+            RpcRecorder::Replay { blocks, .. } => {
+                Some(blocks.get(dataset.value()).cloned().ok_or_else(|| {
+                    IndexerError::Rpc(format!(
+                        "no {} block recorded in the replayed fixture",
+                        dataset.value()
+                    ))
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn replay_call(
+        &self,
+        block: &BlockInfo,
+        contract: &str,
+        method: &str,
+        args: &FunctionArgs,
+    ) -> Option<IndexerResult<RpcQueryResponse>> {
+        let calls = match self {
+            RpcRecorder::Replay { calls, .. } => calls,
+            _ => return None,
+        };
+
+        let args = decode_args(args);
+        let response = match calls.get(&call_key(block.height, contract, method, &args)) {
+            Some(Ok(result)) => serde_json::from_value::<RpcQueryResponse>(json!({
+                "result": serde_json::to_vec(result).unwrap_or_default(),
+                "logs": [],
+                "block_height": block.height,
+                "block_hash": block.hash,
+            }))
+            .map_err(IndexerError::from),
+            Some(Err(error)) => Err(IndexerError::Rpc(error.clone())),
+            None => Err(IndexerError::Rpc(format!(
+                "no recorded response for {}.{}({}) at block {}",
+                contract, method, args, block.height
+            ))),
+        };
+
+        Some(response)
+    }
+}
+
+// Called once from `rocket()` so a missing fixture fails at startup instead of on the first call
+pub fn init_recorder(config: &RpcConfig) -> IndexerResult<&'static RpcRecorder> {
+    let recorder = RpcRecorder::new(config)?;
+    Ok(RECORDER.get_or_init(|| recorder))
+}
+
+pub fn recorder() -> &'static RpcRecorder {
+    RECORDER.get_or_init(|| RpcRecorder::new(rpc()).expect("invalid RPC fixture"))
+}
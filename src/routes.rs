@@ -1,89 +1,361 @@
 use crate::*;
 
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Shutdown, State};
+
 #[get("/")]
 pub async fn root() -> String {
-    format!("Hello world")
+    String::from("Hello world")
+}
+
+// Liveness only tells the process still serves requests, dependencies are left to readiness
+#[get("/health/live")]
+pub async fn health_live() -> Json<rocket::serde::json::Value> {
+    Json(json!({ "status": "ok" }))
 }
 
-#[get("/list-farms")]
-pub async fn list_farms() -> Json<Vec<FarmInfo>> {
-    let farms_map = get_redis_farms().await;
+#[get("/health/ready")]
+pub async fn health_ready(
+    config: &State<HealthConfig>,
+    redis: &State<Option<Arc<RedisPool>>>,
+    mongo: &State<Option<Arc<MongoPool>>>,
+    scheduler: &State<Arc<Scheduler>>,
+) -> (Status, Json<Readiness>) {
+    let report = readiness(config, redis.as_deref(), mongo.as_deref(), scheduler).await;
 
-    let farms = farms_map.values().cloned().collect();
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
 
-    Json(farms)
+    (status, Json(report))
 }
 
-#[get("/list-pools")]
-pub async fn list_pools() -> Json<Vec<PoolInfo>> {
-    let pools_map = get_redis_pools().await;
+#[get("/block")]
+pub async fn block(
+    store: &State<Arc<dyn Store>>,
+) -> IndexerResult<Json<BTreeMap<String, BlockInfo>>> {
+    let blocks = store.get_blocks().await?;
+
+    Ok(Json(blocks))
+}
 
-    let pools = pools_map.values().cloned().collect();
+#[get("/scheduler")]
+pub async fn scheduler_status(
+    scheduler: &State<Arc<Scheduler>>,
+) -> Json<BTreeMap<String, RefreshStatus>> {
+    Json(scheduler.status())
+}
 
-    Json(pools)
+// Tokens and seeds that couldn't be fetched by the latest refresh of each dataset
+#[get("/failures")]
+pub async fn fetch_failures(
+    scheduler: &State<Arc<Scheduler>>,
+) -> Json<BTreeMap<String, Vec<FetchFailure>>> {
+    Json(scheduler.fetch_failures())
 }
 
-#[get("/whitelisted-tokens")]
-pub async fn list_whitelisted_tokens() -> Json<Vec<FungibleTokenMetadata>> {
-    let tokens_map = get_redis_tokens_metadata().await;
+// Prometheus text exposition format
+#[get("/metrics")]
+pub async fn prometheus_metrics() -> IndexerResult<(ContentType, String)> {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
 
-    if tokens_map.is_empty() {
-        println!("ERR_FETCHING_TOKENS_METADATA");
-        return Json(vec![]);
+    Ok((content_type, metrics().encode()?))
+}
+
+#[get("/rpc")]
+pub async fn rpc_health() -> Json<Vec<EndpointHealth>> {
+    Json(rpc_pool().health())
+}
+
+#[get("/list-farms?<query..>")]
+pub async fn list_farms(
+    store: &State<Arc<dyn Store>>,
+    query: FarmQuery,
+) -> IndexerResult<Paged<FarmInfo>> {
+    let mut farms = store.get_farms().await?;
+    let pools = store.get_pools().await?;
+    let prices = store.get_prices().await?;
+
+    set_farm_aprs(&mut farms, &pools, &prices);
+
+    let farms = filter_farms(farms, &query);
+
+    Ok(paginate(farms, query.offset, query.limit))
+}
+
+#[get("/list-pools?<query..>")]
+pub async fn list_pools(
+    store: &State<Arc<dyn Store>>,
+    query: PoolQuery,
+) -> IndexerResult<Paged<PoolInfo>> {
+    let pools = store.get_pools().await?;
+
+    let pools = filter_pools(pools, &query);
+
+    Ok(paginate(pools, query.offset, query.limit))
+}
+
+#[get("/tvl")]
+pub async fn tvl(store: &State<Arc<dyn Store>>) -> IndexerResult<Json<ProtocolTvl>> {
+    let pools = store.get_pools().await?;
+    let prices = store.get_prices().await?;
+
+    Ok(Json(get_protocol_tvl(&pools, &prices)))
+}
+
+fn parse_amount_in(amount_in: &str) -> IndexerResult<u128> {
+    parse_amount(amount_in)
+        .map_err(|_| IndexerError::BadRequest(format!("invalid amount_in {}", amount_in)))
+}
+
+// Stored metadata covers whitelisted tokens only, the other pool tokens fall back to what the
+// pool refreshes fetched
+async fn pool_token_metadata(
+    store: &Arc<dyn Store>,
+    fetched: &TokenMetadataCache,
+    pools: &[PoolInfo],
+) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+    let mut token_metadata = store.get_tokens().await?;
+
+    for token in pools.iter().flat_map(|pool| pool.token_account_ids.iter()) {
+        if !token_metadata.contains_key(token) {
+            if let Some(metadata) = fetched.get(token) {
+                token_metadata.insert(token.clone(), metadata);
+            }
+        }
     }
 
-    let tokens = tokens_map.values().cloned().collect();
+    Ok(token_metadata)
+}
+
+#[get("/quote?<pool_id>&<token_in>&<token_out>&<amount_in>")]
+pub async fn quote(
+    store: &State<Arc<dyn Store>>,
+    metadata: &State<Arc<TokenMetadataCache>>,
+    pool_id: u64,
+    token_in: String,
+    token_out: String,
+    amount_in: String,
+) -> IndexerResult<Json<SwapQuote>> {
+    let amount_in = parse_amount_in(&amount_in)?;
+
+    let pool = store
+        .get_pool(pool_id)
+        .await?
+        .ok_or_else(|| IndexerError::NotFound(format!("pool {}", pool_id)))?;
+    let token_metadata = pool_token_metadata(store, metadata, std::slice::from_ref(&pool)).await?;
+
+    let quote = get_swap_quote(&pool, &token_metadata, &token_in, &token_out, amount_in)
+        .ok_or_else(|| {
+            let tokens = &pool.token_account_ids;
+
+            match unknown_decimals(&pool, &token_metadata) {
+                Some(token) if tokens.contains(&token_in) && tokens.contains(&token_out) => {
+                    IndexerError::Unavailable(format!(
+                        "decimals of {} in pool {} aren't known yet",
+                        token, pool_id
+                    ))
+                }
+                _ => IndexerError::BadRequest(format!(
+                    "pool {} can't swap {} to {}",
+                    pool_id, token_in, token_out
+                )),
+            }
+        })?;
 
-    Json(tokens)
+    Ok(Json(quote))
 }
 
-#[get("/mongo-list-farms")]
-pub async fn mongo_list_farms() -> Json<Vec<FarmInfo>> {
-    let farms = mongo_get_farms().await.unwrap_or_default();
+#[get("/route?<token_in>&<token_out>&<amount_in>&<max_hops>")]
+pub async fn route(
+    store: &State<Arc<dyn Store>>,
+    metadata: &State<Arc<TokenMetadataCache>>,
+    token_in: String,
+    token_out: String,
+    amount_in: String,
+    max_hops: Option<usize>,
+) -> IndexerResult<Json<SwapRoute>> {
+    let amount_in = parse_amount_in(&amount_in)?;
 
-    Json(farms)
+    let pools = store.get_pools().await?;
+    let token_metadata = pool_token_metadata(store, metadata, &pools).await?;
+
+    let route = find_best_route(
+        &pools,
+        &token_metadata,
+        &token_in,
+        &token_out,
+        amount_in,
+        max_hops.unwrap_or(DEFAULT_MAX_HOPS),
+    )
+    .ok_or_else(|| {
+        IndexerError::NotFound(format!("no route from {} to {}", token_in, token_out))
+    })?;
+
+    Ok(Json(route))
+}
+
+#[get("/pools/<pool_id>")]
+pub async fn get_pool(
+    store: &State<Arc<dyn Store>>,
+    pool_id: u64,
+) -> IndexerResult<Json<PoolInfo>> {
+    let pool = store
+        .get_pool(pool_id)
+        .await?
+        .ok_or_else(|| IndexerError::NotFound(format!("pool {}", pool_id)))?;
+
+    Ok(Json(pool))
+}
+
+#[get("/farms/<farm_id>")]
+pub async fn get_farm(
+    store: &State<Arc<dyn Store>>,
+    farm_id: String,
+) -> IndexerResult<Json<FarmInfo>> {
+    let farm = store
+        .get_farm(&farm_id)
+        .await?
+        .ok_or_else(|| IndexerError::NotFound(format!("farm {}", farm_id)))?;
+
+    // Only the seed pool is needed for the APR
+    let mut pools: Vec<PoolInfo> = Vec::new();
+    if let Some(pool_id) = seed_pool_id(&farm.seed_id) {
+        if let Some(pool) = store.get_pool(pool_id).await? {
+            pools.push(pool);
+        }
+    }
+    let prices = store.get_prices().await?;
+
+    let mut farms = vec![farm];
+    set_farm_aprs(&mut farms, &pools, &prices);
+
+    farms
+        .pop()
+        .map(Json)
+        .ok_or_else(|| IndexerError::NotFound(format!("farm {}", farm_id)))
 }
 
-#[get("/mongo-list-pools")]
-pub async fn mongo_list_pools() -> Json<Vec<PoolInfo>> {
-    let pools = mongo_get_pools().await.unwrap_or_default();
+#[get("/tokens/<account_id>")]
+pub async fn get_token(
+    store: &State<Arc<dyn Store>>,
+    account_id: String,
+) -> IndexerResult<Json<FungibleTokenMetadata>> {
+    let mut metadata = store
+        .get_token(&account_id)
+        .await?
+        .ok_or_else(|| IndexerError::NotFound(format!("token {}", account_id)))?;
+
+    let prices = store.get_prices().await?;
+    metadata.price = prices.get(&account_id).map(|info| info.price.clone());
 
-    Json(pools)
+    Ok(Json(metadata))
 }
 
-#[get("/mongo-whitelisted-tokens")]
-pub async fn mongo_list_whitelisted_tokens() -> Json<Vec<FungibleTokenMetadata>> {
-    let tokens = mongo_get_tokens_metadata().await.unwrap_or_default();
+#[get("/pools/<pool_id>/history?<from>&<to>")]
+pub async fn pool_history(
+    store: &State<Arc<dyn Store>>,
+    pool_id: u64,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> IndexerResult<Json<Vec<PoolSnapshot>>> {
+    let history = store.get_pool_history(pool_id, from, to).await?;
 
-    Json(tokens)
+    Ok(Json(history))
 }
 
-type Result<T, E = rocket::response::Debug<Box<dyn std::error::Error>>> = std::result::Result<T, E>;
+#[get("/farms/<farm_id>/history?<from>&<to>")]
+pub async fn farm_history(
+    store: &State<Arc<dyn Store>>,
+    farm_id: String,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> IndexerResult<Json<Vec<FarmSnapshot>>> {
+    let history = store.get_farm_history(&farm_id, from, to).await?;
 
-#[get("/init-redis")]
-pub async fn init_redis() -> Result<()> {
-    // TODO: improve this by collecting string results, like "Ok"
-    println!("Redis is starting");
-    let result = redis_update_tokens_metadata(None).await.expect("Done");
-    println!("Get tokens finished");
-    let result = redis_update_farms().await.expect("Done");
-    println!("Get farms finished");
-    let result = redis_update_pools().await.expect("Done");
-    println!("Get pools finished");
+    Ok(Json(history))
+}
+
+#[get("/tokens/<account_id>/history?<from>&<to>")]
+pub async fn token_history(
+    store: &State<Arc<dyn Store>>,
+    account_id: String,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> IndexerResult<Json<Vec<TokenSnapshot>>> {
+    let history = store.get_token_history(&account_id, from, to).await?;
 
-    Ok(())
+    Ok(Json(history))
 }
 
-#[get("/init-mongo")]
-pub async fn init_mongo() -> Result<()> {
-    // TODO: improve this by collecting string results, like "Ok"
-    println!("Mongo is starting");
-    let result = mongo_add_tokens().await.expect("Done");
-    println!("Redis is starting");
-    let result = mongo_add_farms().await.expect("Done");
-    println!("Get farms finished");
-    let result = mongo_add_pools().await.expect("Done");
-    println!("Get pools finished");
+#[get("/whitelisted-tokens?<query..>")]
+pub async fn list_whitelisted_tokens(
+    store: &State<Arc<dyn Store>>,
+    query: TokenQuery,
+) -> IndexerResult<Paged<FungibleTokenMetadata>> {
+    let mut tokens_map = store.get_tokens().await?;
 
-    Ok(())
+    if tokens_map.is_empty() {
+        println!("ERR_FETCHING_TOKENS_METADATA");
+    }
+
+    let prices = store.get_prices().await?;
+
+    for (token, metadata) in tokens_map.iter_mut() {
+        metadata.price = prices.get(token).map(|info| info.price.clone());
+    }
+
+    let tokens = filter_tokens(tokens_map, &query)
+        .into_iter()
+        .map(|(_, metadata)| metadata)
+        .collect();
+
+    Ok(paginate(tokens, query.offset, query.limit))
+}
+
+// Refreshes every dataset right away, through the scheduler so it can't overlap a scheduled run
+#[get("/init")]
+pub async fn init(scheduler: &State<Arc<Scheduler>>) -> Json<BTreeMap<String, RefreshStatus>> {
+    for dataset in Dataset::all() {
+        println!("Refreshing {}", dataset.value());
+        scheduler.inner().clone().run(dataset).await;
+    }
+
+    Json(scheduler.status())
+}
+
+// Pushes the pools and farms changed by each refresh. A subscriber that falls too far behind
+// receives a `lagged` event and should reload the lists
+#[get("/events?<query..>")]
+pub fn events(
+    feed: &State<Arc<UpdateFeed>>,
+    query: EventQuery,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = feed.subscribe();
+
+    EventStream! {
+        loop {
+            let event = select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(skipped)) => {
+                        yield Event::json(&json!({ "skipped": skipped })).event("lagged");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            if let Some(event) = query.apply(&event) {
+                yield Event::json(&event).event(event.dataset.clone());
+            }
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use crate::*;
+
+/***************************
+    Multi-hop routing
+****************************/
+
+pub const DEFAULT_MAX_HOPS: usize = 3;
+
+// Each extra hop multiplies the search space, keep requests bounded
+pub const MAX_HOPS: usize = 4;
+
+#[derive(Clone)]
+struct Path {
+    amount: u128,
+    tokens: Vec<String>,
+    pools: Vec<usize>,
+}
+
+// token -> (index in `pools`, token reachable through that pool)
+fn token_graph(pools: &[PoolInfo]) -> HashMap<String, Vec<(usize, String)>> {
+    let mut graph: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+
+    for (idx, pool) in pools.iter().enumerate() {
+        for token_in in pool.token_account_ids.iter() {
+            for token_out in pool.token_account_ids.iter() {
+                if token_in != token_out {
+                    graph
+                        .entry(token_in.clone())
+                        .or_default()
+                        .push((idx, token_out.clone()));
+                }
+            }
+        }
+    }
+
+    graph
+}
+
+// Relaxes the graph one hop at a time keeping the best amount reached for every token, which
+// avoids enumerating every path while still finding routes through intermediate tokens.
+pub fn find_best_route(
+    pools: &[PoolInfo],
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+    token_in: &str,
+    token_out: &str,
+    amount_in: u128,
+    max_hops: usize,
+) -> Option<SwapRoute> {
+    if token_in == token_out || amount_in == 0 {
+        return None;
+    }
+
+    let graph = token_graph(pools);
+
+    let mut frontier: HashMap<String, Path> = HashMap::new();
+    frontier.insert(
+        token_in.to_string(),
+        Path {
+            amount: amount_in,
+            tokens: vec![token_in.to_string()],
+            pools: vec![],
+        },
+    );
+
+    let mut best: Option<Path> = None;
+
+    for _ in 0..max_hops.min(MAX_HOPS) {
+        let mut next: HashMap<String, Path> = HashMap::new();
+
+        for (token, path) in frontier.iter() {
+            let edges = match graph.get(token) {
+                Some(edges) => edges,
+                None => continue,
+            };
+
+            for (pool_idx, next_token) in edges.iter() {
+                // A pool is quoted against its reserves before the route, a second hop through it
+                // would be priced on stale reserves
+                if path.tokens.contains(next_token) || path.pools.contains(pool_idx) {
+                    continue;
+                }
+
+                let amount = match get_pool_return(
+                    &pools[*pool_idx],
+                    token_metadata,
+                    token,
+                    next_token,
+                    path.amount,
+                ) {
+                    Some(amount) if amount > 0 => amount,
+                    _ => continue,
+                };
+
+                let better = match next.get(next_token) {
+                    Some(current) => amount > current.amount,
+                    None => true,
+                };
+
+                if better {
+                    let mut extended = path.clone();
+                    extended.amount = amount;
+                    extended.tokens.push(next_token.clone());
+                    extended.pools.push(*pool_idx);
+                    next.insert(next_token.clone(), extended);
+                }
+            }
+        }
+
+        if let Some(path) = next.remove(token_out) {
+            let better = match &best {
+                Some(current) => path.amount > current.amount,
+                None => true,
+            };
+
+            if better {
+                best = Some(path);
+            }
+        }
+
+        if next.is_empty() {
+            break;
+        }
+
+        frontier = next;
+    }
+
+    let best = best?;
+
+    let mut hops: Vec<SwapQuote> = Vec::new();
+    let mut amount = amount_in;
+    let mut kept: f64 = 1.0;
+
+    for (hop, pool_idx) in best.pools.iter().enumerate() {
+        let quote = get_swap_quote(
+            &pools[*pool_idx],
+            token_metadata,
+            &best.tokens[hop],
+            &best.tokens[hop + 1],
+            amount,
+        )?;
+
+        amount = quote.amount_out.parse::<u128>().ok()?;
+        kept *= 1.0 - quote.price_impact;
+        hops.push(quote);
+    }
+
+    Some(SwapRoute {
+        token_in: token_in.to_string(),
+        token_out: token_out.to_string(),
+        amount_in: amount_in.to_string(),
+        amount_out: amount.to_string(),
+        pool_ids: hops.iter().map(|hop| hop.pool_id).collect(),
+        hops,
+        price_impact: 1.0 - kept,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simple_pool(id: u64, tokens: &[&str], amounts: &[&str]) -> PoolInfo {
+        PoolInfo {
+            id: Some(id),
+            pool_kind: String::from("SIMPLE_POOL"),
+            token_account_ids: tokens.iter().map(|token| token.to_string()).collect(),
+            amounts: amounts.iter().map(|amount| amount.to_string()).collect(),
+            total_fee: 30,
+            shares_total_supply: String::from("0"),
+            amp: 0,
+            farming: None,
+            token_symbols: None,
+            tvl: None,
+        }
+    }
+
+    // a -> b in the 4 token pool, b -> x in the cheap pool and x -> c back in the 4 token pool
+    // would beat the direct swap, but it goes through pool 0 twice
+    #[test]
+    fn routes_never_reuse_a_pool() {
+        let tokens = BTreeMap::new();
+        let pools = vec![
+            simple_pool(
+                0,
+                &["a.testnet", "b.testnet", "x.testnet", "c.testnet"],
+                &["1000000", "1000000", "1000000", "1000000"],
+            ),
+            simple_pool(1, &["b.testnet", "x.testnet"], &["1000000", "100000000"]),
+        ];
+
+        let route = find_best_route(&pools, &tokens, "a.testnet", "c.testnet", 1000, 3)
+            .expect("direct route");
+
+        assert_eq!(route.pool_ids, vec![0]);
+        assert_eq!(
+            Some(route.amount_out.parse::<u128>().unwrap()),
+            get_pool_return(&pools[0], &tokens, "a.testnet", "c.testnet", 1000)
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use crate::*;
+
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use near_jsonrpc_client::errors::{
+    JsonRpcError, JsonRpcServerError, JsonRpcServerResponseStatusError,
+};
+use near_jsonrpc_client::methods::RpcMethod;
+
+/***************************
+    RPC endpoints
+****************************/
+
+static POOL: OnceLock<RpcPool> = OnceLock::new();
+
+// Token bucket refilled at `rate` tokens per second, holding up to one second worth of calls
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            tokens: rate.max(1.0),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes a token, or tells how long until one is available
+    fn try_acquire(&mut self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+struct RpcEndpoint {
+    url: String,
+    client: JsonRpcClient,
+    limiter: Mutex<RateLimiter>,
+    health: Mutex<EndpointHealth>,
+}
+
+impl RpcEndpoint {
+    // Waits until the endpoint's rate limit lets another call through
+    async fn throttle(&self) {
+        let started = Instant::now();
+        let mut throttled = false;
+
+        loop {
+            let wait = self.limiter.lock().unwrap().try_acquire();
+
+            match wait {
+                Some(wait) => {
+                    throttled = true;
+                    tokio::time::sleep(wait).await;
+                }
+                None => break,
+            }
+        }
+
+        if throttled {
+            metrics()
+                .rpc_throttled
+                .with_label_values(&[&self.url])
+                .inc();
+
+            let mut health = self.health.lock().unwrap();
+            health.throttled_calls += 1;
+            health.throttled_wait_ms += started.elapsed().as_millis() as u64;
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap().healthy
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+
+        health.calls += 1;
+        health.consecutive_failures = 0;
+        health.healthy = true;
+        health.unhealthy_until = None;
+    }
+
+    fn record_failure(&self, config: &RpcConfig, err: &str) {
+        let mut health = self.health.lock().unwrap();
+        let now = now_secs();
+
+        health.calls += 1;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(err.to_string());
+        health.last_failure_at = Some(now);
+
+        if health.consecutive_failures >= config.failure_threshold {
+            if health.healthy {
+                println!("ERR_RPC_ENDPOINT_UNHEALTHY: {}", health.url);
+            }
+            health.healthy = false;
+            health.unhealthy_until = Some(now + config.unhealthy_cooldown.as_secs());
+        }
+    }
+
+    // An unhealthy endpoint gets another chance once its cooldown is over
+    fn refresh(&self) {
+        let mut health = self.health.lock().unwrap();
+
+        if let Some(until) = health.unhealthy_until {
+            if now_secs() >= until {
+                health.healthy = true;
+                health.unhealthy_until = None;
+            }
+        }
+    }
+}
+
+// The RPC endpoints in priority order. Calls go to the first healthy endpoint, fail over to the
+// next one on transient or endpoint specific errors and are retried with exponential backoff once
+// all were tried.
+// Each endpoint has its own rate limit, a call waits for it before being sent.
+pub struct RpcPool {
+    config: RpcConfig,
+    endpoints: Vec<RpcEndpoint>,
+}
+
+impl RpcPool {
+    pub fn new(config: RpcConfig) -> RpcPool {
+        let endpoints = config
+            .endpoints
+            .iter()
+            .zip(config.rate_limits.iter())
+            .map(|(url, rate)| RpcEndpoint {
+                url: url.clone(),
+                client: JsonRpcClient::connect(url),
+                limiter: Mutex::new(RateLimiter::new(*rate)),
+                health: Mutex::new(EndpointHealth {
+                    url: url.clone(),
+                    healthy: true,
+                    rate_limit: *rate,
+                    ..Default::default()
+                }),
+            })
+            .collect();
+
+        RpcPool { config, endpoints }
+    }
+
+    pub fn health(&self) -> Vec<EndpointHealth> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.health.lock().unwrap().clone())
+            .collect()
+    }
+
+    // Healthy endpoints first, unhealthy ones are still tried as a last resort
+    fn candidates(&self) -> Vec<&RpcEndpoint> {
+        for endpoint in self.endpoints.iter() {
+            endpoint.refresh();
+        }
+
+        let (healthy, unhealthy): (Vec<&RpcEndpoint>, Vec<&RpcEndpoint>) = self
+            .endpoints
+            .iter()
+            .partition(|endpoint| endpoint.is_healthy());
+
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.config.retry_delay * 2u32.saturating_pow(retry.min(16))
+    }
+
+    // `request` builds the request again for every attempt
+    pub async fn call<M, F>(&self, request: F) -> IndexerResult<M::Response>
+    where
+        M: RpcMethod,
+        F: Fn() -> M,
+        M::Error: Serialize,
+        JsonRpcError<M::Error>: fmt::Display,
+    {
+        let mut last_error = String::from("no RPC endpoint configured");
+
+        for retry in 0..=self.config.max_retries {
+            if retry > 0 {
+                tokio::time::sleep(self.backoff(retry - 1)).await;
+            }
+
+            for endpoint in self.candidates() {
+                let url = &endpoint.url;
+
+                endpoint.throttle().await;
+
+                let err = match tokio::time::timeout(
+                    self.config.timeout,
+                    endpoint.client.call(request()),
+                )
+                .await
+                {
+                    Ok(Ok(response)) => {
+                        endpoint.record_success();
+                        return Ok(response);
+                    }
+                    Ok(Err(err)) if is_endpoint_specific(&err) => {
+                        // The node is up, it just can't serve this block
+                        endpoint.record_success();
+                        println!("ERR_RPC_CALL {}: {}", url, err);
+                        last_error = format!("{}: {}", url, err);
+                        continue;
+                    }
+                    Ok(Err(err)) if !is_transient(&err) => {
+                        // The node answered, another one would answer the same
+                        endpoint.record_success();
+                        return Err(IndexerError::Rpc(format!("{}: {}", url, err)));
+                    }
+                    Ok(Err(err)) => err.to_string(),
+                    Err(_) => format!("timed out after {:?}", self.config.timeout),
+                };
+
+                println!("ERR_RPC_CALL {}: {}", url, err);
+                endpoint.record_failure(&self.config, &err);
+                last_error = format!("{}: {}", url, err);
+            }
+        }
+
+        Err(IndexerError::Rpc(format!(
+            "gave up after {} retries, last error {}",
+            self.config.max_retries, last_error
+        )))
+    }
+
+    // Readiness probe: one `status` call per endpoint until one answers. It counts against the
+    // endpoint's rate limit like any call, but skips the recorder, the retries and the endpoint
+    // health, so probing doesn't touch refreshes
+    pub async fn probe(&self) -> IndexerResult<()> {
+        let mut last_error = String::from("no RPC endpoint configured");
+
+        for endpoint in self.endpoints.iter() {
+            endpoint.throttle().await;
+
+            match endpoint
+                .client
+                .call(methods::status::RpcStatusRequest)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(err) => last_error = format!("{}: {}", endpoint.url, err),
+            }
+        }
+
+        Err(IndexerError::Rpc(last_error))
+    }
+}
+
+// Worth another try: the node was unreachable, overloaded or failed internally
+fn is_transient<E>(err: &JsonRpcError<E>) -> bool {
+    match err {
+        JsonRpcError::TransportError(_) => true,
+        JsonRpcError::ServerError(JsonRpcServerError::InternalError { .. }) => true,
+        JsonRpcError::ServerError(JsonRpcServerError::ResponseStatusError(status)) => {
+            !matches!(status, JsonRpcServerResponseStatusError::Unauthorized)
+        }
+        _ => false,
+    }
+}
+
+// Calls are pinned to a block: a lagging node may not know it yet and a non-archival one may have
+// garbage collected it, while another endpoint can still answer
+fn is_endpoint_specific<E: Serialize>(err: &JsonRpcError<E>) -> bool {
+    let handler_error = match err {
+        JsonRpcError::ServerError(JsonRpcServerError::HandlerError(handler_error)) => handler_error,
+        _ => return false,
+    };
+
+    // Handler errors serialize as `{"name": "UNKNOWN_BLOCK", "info": ...}`
+    let name = serde_json::to_value(handler_error)
+        .ok()
+        .and_then(|value| value.get("name")?.as_str().map(String::from));
+
+    matches!(
+        name.as_deref(),
+        Some("UNKNOWN_BLOCK" | "GARBAGE_COLLECTED_BLOCK" | "NOT_SYNCED_YET")
+    )
+}
+
+pub fn rpc_pool() -> &'static RpcPool {
+    POOL.get_or_init(|| RpcPool::new(rpc().clone()))
+}
@@ -0,0 +1,183 @@
+use crate::*;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use rocket::fairing::AdHoc;
+
+/***************************
+    Background refreshes
+****************************/
+
+pub struct Scheduler {
+    config: SchedulerConfig,
+    store: Arc<dyn Store>,
+    feed: Arc<UpdateFeed>,
+    metadata: Arc<TokenMetadataCache>,
+    status: Mutex<BTreeMap<String, RefreshStatus>>,
+    // Items left out by the latest successful refresh of each dataset
+    fetch_failures: Mutex<BTreeMap<String, Vec<FetchFailure>>>,
+}
+
+impl Scheduler {
+    pub fn new(
+        config: SchedulerConfig,
+        store: Arc<dyn Store>,
+        feed: Arc<UpdateFeed>,
+        metadata: Arc<TokenMetadataCache>,
+    ) -> Scheduler {
+        let mut status: BTreeMap<String, RefreshStatus> = BTreeMap::new();
+
+        for dataset in Dataset::all() {
+            let entry = RefreshStatus {
+                interval_secs: config.interval(&dataset).as_secs(),
+                ..Default::default()
+            };
+            status.insert(dataset.value().to_string(), entry);
+        }
+
+        Scheduler {
+            config,
+            store,
+            feed,
+            metadata,
+            status: Mutex::new(status),
+            fetch_failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn status(&self) -> BTreeMap<String, RefreshStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn fetch_failures(&self) -> BTreeMap<String, Vec<FetchFailure>> {
+        self.fetch_failures.lock().unwrap().clone()
+    }
+
+    // Marks the dataset as running, unless a previous refresh still is
+    fn try_start(&self, dataset: &Dataset) -> bool {
+        let mut status = self.status.lock().unwrap();
+        let entry = status.entry(dataset.value().to_string()).or_default();
+
+        if entry.running {
+            entry.skipped += 1;
+            return false;
+        }
+
+        entry.running = true;
+        entry.last_started_at = Some(now_secs());
+
+        true
+    }
+
+    fn finish(
+        &self,
+        dataset: &Dataset,
+        started: Instant,
+        result: Result<(BlockInfo, Vec<FetchFailure>), String>,
+    ) {
+        metrics().observe_refresh(dataset, started, result.is_ok());
+
+        let mut status = self.status.lock().unwrap();
+        let entry = status.entry(dataset.value().to_string()).or_default();
+
+        entry.running = false;
+        entry.runs += 1;
+        entry.last_finished_at = Some(now_secs());
+        entry.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+
+        match result {
+            Ok((block, fetch_failures)) => {
+                entry.last_success_at = entry.last_finished_at;
+                entry.last_block = Some(block);
+                entry.last_error = None;
+                entry.fetch_failures = fetch_failures.len() as u64;
+
+                self.fetch_failures
+                    .lock()
+                    .unwrap()
+                    .insert(dataset.value().to_string(), fetch_failures);
+            }
+            Err(err) => {
+                entry.failures += 1;
+                entry.last_error = Some(err);
+            }
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, dataset: Dataset) {
+        if !self.try_start(&dataset) {
+            println!("Refresh of {} still running, skipping", dataset.value());
+            return;
+        }
+
+        let started = Instant::now();
+        let store = self.store.clone();
+        let feed = self.feed.clone();
+        let metadata = self.metadata.clone();
+
+        // Spawned so a panic in the refresh is reported instead of killing the scheduler
+        let task = tokio::spawn(async move {
+            refresh_dataset(store.as_ref(), feed.as_ref(), metadata.as_ref(), &dataset)
+                .await
+                .map_err(|err| err.to_string())
+        });
+
+        let result = match task.await {
+            Ok(result) => result,
+            Err(err) => Err(format!("refresh task failed: {}", err)),
+        };
+
+        if let Err(err) = &result {
+            println!("ERR_REFRESHING_{}: {}", dataset.value().to_uppercase(), err);
+        }
+
+        self.finish(&dataset, started, result);
+    }
+
+    fn jitter(&self) -> Duration {
+        let max = self.config.jitter.as_millis() as u64;
+
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max))
+    }
+
+    pub fn start(self: Arc<Self>) {
+        if !self.config.enabled {
+            println!("Scheduler disabled");
+            return;
+        }
+
+        for dataset in Dataset::all() {
+            let interval = self.config.interval(&dataset);
+            if interval.is_zero() {
+                continue;
+            }
+
+            let scheduler = self.clone();
+
+            // Ticks don't wait for the refresh, a slow run makes the next tick skip instead
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(scheduler.jitter()).await;
+                    tokio::spawn(scheduler.clone().run(dataset));
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_liftoff("Refresh scheduler", |rocket| {
+            Box::pin(async move {
+                if let Some(scheduler) = rocket.state::<Arc<Scheduler>>() {
+                    scheduler.clone().start();
+                }
+            })
+        })
+    }
+}
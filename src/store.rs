@@ -0,0 +1,327 @@
+use crate::*;
+
+use rocket::fairing::AdHoc;
+use std::time::Duration;
+
+/***************************
+    Storage backends
+****************************/
+
+// Data is fetched from chain once per refresh and handed to the store, so a write-through
+// setup never queries the RPC twice
+#[rocket::async_trait]
+pub trait Store: Send + Sync {
+    fn name(&self) -> String;
+
+    // Prepares the backend (indexes, schemas) before the first refresh
+    async fn init(&self) -> IndexerResult<()> {
+        Ok(())
+    }
+
+    // Each write replaces the dataset: ids the refresh no longer lists are deleted, except tokens
+    // and farms of seeds listed in `failures`, which couldn't be fetched this time
+    async fn store_tokens(
+        &self,
+        block: &BlockInfo,
+        tokens: &BTreeMap<String, FungibleTokenMetadata>,
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()>;
+
+    async fn store_farms(
+        &self,
+        block: &BlockInfo,
+        farms: &[FarmInfo],
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()>;
+
+    async fn store_pools(
+        &self,
+        block: &BlockInfo,
+        pools: &[PoolInfo],
+        prices: &BTreeMap<String, TokenInfo>,
+    ) -> IndexerResult<()>;
+
+    async fn get_tokens(&self) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>>;
+
+    async fn get_prices(&self) -> IndexerResult<BTreeMap<String, TokenInfo>>;
+
+    async fn get_farms(&self) -> IndexerResult<Vec<FarmInfo>>;
+
+    async fn get_pools(&self) -> IndexerResult<Vec<PoolInfo>>;
+
+    async fn get_token(&self, account_id: &str) -> IndexerResult<Option<FungibleTokenMetadata>>;
+
+    async fn get_farm(&self, farm_id: &str) -> IndexerResult<Option<FarmInfo>>;
+
+    async fn get_pool(&self, pool_id: u64) -> IndexerResult<Option<PoolInfo>>;
+
+    async fn get_blocks(&self) -> IndexerResult<BTreeMap<String, BlockInfo>>;
+
+    // History ranges are unix timestamps in seconds, both ends inclusive and optional. The latest
+    // snapshot at or before `from` comes first, it holds the state at the start of the range
+    async fn get_pool_history(
+        &self,
+        pool_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<PoolSnapshot>>;
+
+    async fn get_farm_history(
+        &self,
+        farm_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<FarmSnapshot>>;
+
+    async fn get_token_history(
+        &self,
+        account_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<TokenSnapshot>>;
+}
+
+// Writes go to every store, reads are served by the primary
+pub struct WriteThroughStore {
+    pub primary: Box<dyn Store>,
+    pub secondary: Box<dyn Store>,
+}
+
+#[rocket::async_trait]
+impl Store for WriteThroughStore {
+    fn name(&self) -> String {
+        format!("{}+{}", self.primary.name(), self.secondary.name())
+    }
+
+    async fn init(&self) -> IndexerResult<()> {
+        self.primary.init().await?;
+        self.secondary.init().await
+    }
+
+    async fn store_tokens(
+        &self,
+        block: &BlockInfo,
+        tokens: &BTreeMap<String, FungibleTokenMetadata>,
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        self.primary.store_tokens(block, tokens, failures).await?;
+        self.secondary.store_tokens(block, tokens, failures).await
+    }
+
+    async fn store_farms(
+        &self,
+        block: &BlockInfo,
+        farms: &[FarmInfo],
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        self.primary.store_farms(block, farms, failures).await?;
+        self.secondary.store_farms(block, farms, failures).await
+    }
+
+    async fn store_pools(
+        &self,
+        block: &BlockInfo,
+        pools: &[PoolInfo],
+        prices: &BTreeMap<String, TokenInfo>,
+    ) -> IndexerResult<()> {
+        self.primary.store_pools(block, pools, prices).await?;
+        self.secondary.store_pools(block, pools, prices).await
+    }
+
+    async fn get_tokens(&self) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+        self.primary.get_tokens().await
+    }
+
+    async fn get_prices(&self) -> IndexerResult<BTreeMap<String, TokenInfo>> {
+        self.primary.get_prices().await
+    }
+
+    async fn get_farms(&self) -> IndexerResult<Vec<FarmInfo>> {
+        self.primary.get_farms().await
+    }
+
+    async fn get_pools(&self) -> IndexerResult<Vec<PoolInfo>> {
+        self.primary.get_pools().await
+    }
+
+    async fn get_token(&self, account_id: &str) -> IndexerResult<Option<FungibleTokenMetadata>> {
+        self.primary.get_token(account_id).await
+    }
+
+    async fn get_farm(&self, farm_id: &str) -> IndexerResult<Option<FarmInfo>> {
+        self.primary.get_farm(farm_id).await
+    }
+
+    async fn get_pool(&self, pool_id: u64) -> IndexerResult<Option<PoolInfo>> {
+        self.primary.get_pool(pool_id).await
+    }
+
+    async fn get_blocks(&self) -> IndexerResult<BTreeMap<String, BlockInfo>> {
+        self.primary.get_blocks().await
+    }
+
+    async fn get_pool_history(
+        &self,
+        pool_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<PoolSnapshot>> {
+        self.primary.get_pool_history(pool_id, from, to).await
+    }
+
+    async fn get_farm_history(
+        &self,
+        farm_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<FarmSnapshot>> {
+        self.primary.get_farm_history(farm_id, from, to).await
+    }
+
+    async fn get_token_history(
+        &self,
+        account_id: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> IndexerResult<Vec<TokenSnapshot>> {
+        self.primary.get_token_history(account_id, from, to).await
+    }
+}
+
+// Entities that are new or differ from their stored version according to `same`. History only
+// records these, so an idle pool or farm doesn't add a snapshot on every refresh
+pub fn changed_entities<T: Clone>(
+    stored: &BTreeMap<String, T>,
+    current: &BTreeMap<String, T>,
+    same: fn(&T, &T) -> bool,
+) -> Vec<(String, T)> {
+    current
+        .iter()
+        .filter(|(id, item)| !stored.get(*id).is_some_and(|stored| same(stored, item)))
+        .map(|(id, item)| (id.clone(), item.clone()))
+        .collect()
+}
+
+// Ids stored before a refresh that it no longer lists, unless `keep` holds for what was stored
+pub fn removed_ids<T>(
+    stored: &BTreeMap<String, T>,
+    current: &BTreeMap<String, T>,
+    keep: impl Fn(&T) -> bool,
+) -> Vec<String> {
+    stored
+        .iter()
+        .filter(|(id, item)| !current.contains_key(*id) && !keep(item))
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+// Items a refresh couldn't fetch with `method`: token contracts or seed ids
+pub fn failed_items(failures: &[FetchFailure], method: Methods) -> BTreeSet<String> {
+    failures
+        .iter()
+        .filter(|failure| failure.method == method.value())
+        .map(|failure| failure.item.clone())
+        .collect()
+}
+
+// Every entity is snapshotted again once per half retention, changed or not, so a history window
+// inside the retention always has a snapshot at or before its start and idle ids don't expire
+pub fn full_snapshot_due(
+    previous: Option<&BlockInfo>,
+    block: &BlockInfo,
+    retention: Duration,
+) -> bool {
+    if retention.is_zero() {
+        return false;
+    }
+
+    let interval = (retention.as_secs() / 2).max(1);
+
+    match previous {
+        Some(previous) => previous.timestamp_secs() / interval != block.timestamp_secs() / interval,
+        None => true,
+    }
+}
+
+// `redis` and `mongo` are the shared clients, present whenever the backend uses them
+pub fn build_store(
+    config: &StorageConfig,
+    redis: Option<Arc<RedisPool>>,
+    mongo: Option<Arc<MongoPool>>,
+) -> IndexerResult<Arc<dyn Store>> {
+    let redis = redis.map(|pool| RedisStore {
+        pool,
+        history_retention: config.redis_history_retention,
+    });
+    let mongo = mongo.map(|pool| MongoStore::new(pool, config.history_retention));
+
+    match (&config.backend, redis, mongo) {
+        (StorageBackend::Redis, Some(redis), _) => Ok(Arc::new(redis)),
+        (StorageBackend::Mongo, _, Some(mongo)) => Ok(Arc::new(mongo)),
+        (StorageBackend::Both, Some(redis), Some(mongo)) => Ok(Arc::new(WriteThroughStore {
+            primary: Box::new(redis),
+            secondary: Box::new(mongo),
+        })),
+        (backend, _, _) => Err(IndexerError::Config(format!(
+            "missing client for the {} backend",
+            backend.value()
+        ))),
+    }
+}
+
+pub fn store_fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Storage", |rocket| {
+        Box::pin(async move {
+            let result = match rocket.state::<Arc<dyn Store>>() {
+                Some(store) => store.init().await.map_err(|err| err.to_string()),
+                None => Ok(()),
+            };
+
+            match result {
+                Ok(_) => Ok(rocket),
+                Err(err) => {
+                    println!("ERR_INITIALIZING_STORAGE: {}", err);
+                    Err(rocket)
+                }
+            }
+        })
+    })
+}
+
+// Fetches one dataset from chain, pinned to a freshly resolved block, stores it and pushes the
+// pools or farms that changed to `feed`. Items that couldn't be fetched are left out and returned
+// alongside the block
+pub async fn refresh_dataset(
+    store: &dyn Store,
+    feed: &UpdateFeed,
+    metadata: &TokenMetadataCache,
+    dataset: &Dataset,
+) -> IndexerResult<(BlockInfo, Vec<FetchFailure>)> {
+    let block = get_final_block(dataset).await?;
+    let mut failures: Vec<FetchFailure> = Vec::new();
+
+    match *dataset {
+        Dataset::Tokens => {
+            let tokens = get_whitelisted_tokens(&block, &mut failures).await?;
+            store.store_tokens(&block, &tokens, &failures).await?;
+            metrics().set_items(dataset, tokens.len());
+        }
+        Dataset::Farms => {
+            let farms = get_farms(&block, &mut failures).await?;
+            feed.load(store).await?;
+            store.store_farms(&block, &farms, &failures).await?;
+            feed.publish_farms(&block, &farms, &failures);
+            metrics().set_items(dataset, farms.len());
+        }
+        Dataset::Pools => {
+            let cached = store.get_tokens().await?;
+            let (pools, prices) = get_pools(&block, &cached, metadata, &mut failures).await?;
+            feed.load(store).await?;
+            store.store_pools(&block, &pools, &prices).await?;
+            feed.publish_pools(&block, &pools);
+            metrics().set_items(dataset, pools.len());
+        }
+    };
+
+    Ok((block, failures))
+}
@@ -0,0 +1,421 @@
+use crate::*;
+
+// The expansion of `construct_uint!` trips clippy lints we can't fix from here
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod u256 {
+    use uint::construct_uint;
+
+    construct_uint! {
+        pub struct U256(4);
+    }
+}
+
+pub use u256::U256;
+
+/***************************
+    Offline swap math
+****************************/
+
+// Same fee precision as the exchange contract, `total_fee` is in basis points
+const FEE_DIVISOR: u128 = 10_000;
+
+// Stable pools compare balances scaled to 18 decimals
+const STABLE_PRECISION: u8 = 18;
+
+const MAX_ITERATIONS: usize = 256;
+
+fn position(pool: &PoolInfo, token: &str) -> Option<usize> {
+    pool.token_account_ids.iter().position(|id| id == token)
+}
+
+fn reserve(pool: &PoolInfo, index: usize) -> Option<u128> {
+    pool.amounts.get(index)?.parse::<u128>().ok()
+}
+
+// x * y = k, with the fee taken from the input like `get_return` on chain
+fn simple_pool_return(
+    pool: &PoolInfo,
+    index_in: usize,
+    index_out: usize,
+    amount_in: u128,
+) -> Option<u128> {
+    let in_balance = U256::from(reserve(pool, index_in)?);
+    let out_balance = U256::from(reserve(pool, index_out)?);
+
+    if in_balance.is_zero() || out_balance.is_zero() {
+        return None;
+    }
+
+    let fee_multiplier = FEE_DIVISOR.checked_sub(pool.total_fee as u128)?;
+    let amount_with_fee = U256::from(amount_in).checked_mul(U256::from(fee_multiplier))?;
+    let denominator = U256::from(FEE_DIVISOR)
+        .checked_mul(in_balance)?
+        .checked_add(amount_with_fee)?;
+    let amount_out = amount_with_fee
+        .checked_mul(out_balance)?
+        .checked_div(denominator)?;
+
+    u128::try_from(amount_out).ok()
+}
+
+// A * n^n, None for a pool without amplification
+fn compute_ann(amp: u64, n_coins: U256) -> Option<U256> {
+    if amp == 0 {
+        return None;
+    }
+
+    U256::from(amp).checked_mul(n_coins.checked_pow(n_coins)?)
+}
+
+fn compute_d(amp: u64, c_amounts: &[U256]) -> Option<U256> {
+    let n_coins = U256::from(c_amounts.len());
+    let mut sum_x = U256::zero();
+    for amount in c_amounts.iter() {
+        sum_x = sum_x.checked_add(*amount)?;
+    }
+
+    if sum_x.is_zero() {
+        return Some(U256::zero());
+    }
+
+    let ann = compute_ann(amp, n_coins)?;
+    let mut d = sum_x;
+
+    for _ in 0..MAX_ITERATIONS {
+        // The contract adds 1 to each term so an empty side doesn't divide by zero
+        let mut d_prod = d;
+        for amount in c_amounts.iter() {
+            d_prod = d_prod
+                .checked_mul(d)?
+                .checked_div(amount.checked_mul(n_coins)?.checked_add(U256::one())?)?;
+        }
+
+        let d_prev = d;
+        let numerator = d.checked_mul(
+            d_prod
+                .checked_mul(n_coins)?
+                .checked_add(sum_x.checked_mul(ann)?)?,
+        )?;
+        let denominator = d
+            .checked_mul(ann.checked_sub(U256::one())?)?
+            .checked_add(d_prod.checked_mul(n_coins.checked_add(U256::one())?)?)?;
+        d = numerator.checked_div(denominator)?;
+
+        if d.max(d_prev) - d.min(d_prev) <= U256::one() {
+            break;
+        }
+    }
+
+    Some(d)
+}
+
+// Balance of `index_out` that keeps the invariant once `index_in` holds `x_c_amount`
+fn compute_y(
+    amp: u64,
+    x_c_amount: U256,
+    c_amounts: &[U256],
+    index_in: usize,
+    index_out: usize,
+) -> Option<U256> {
+    let n_coins = U256::from(c_amounts.len());
+    let ann = compute_ann(amp, n_coins)?;
+    let d = compute_d(amp, c_amounts)?;
+
+    let mut c = d;
+    let mut s = U256::zero();
+
+    for (idx, amount) in c_amounts.iter().enumerate() {
+        let x = if idx == index_in {
+            x_c_amount
+        } else if idx != index_out {
+            *amount
+        } else {
+            continue;
+        };
+
+        s = s.checked_add(x)?;
+        c = c.checked_mul(d)?.checked_div(x.checked_mul(n_coins)?)?;
+    }
+
+    c = c.checked_mul(d)?.checked_div(ann.checked_mul(n_coins)?)?;
+    let b = s.checked_add(d.checked_div(ann)?)?;
+
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        let numerator = y.checked_mul(y)?.checked_add(c)?;
+        let denominator = U256::from(2)
+            .checked_mul(y)?
+            .checked_add(b)?
+            .checked_sub(d)?;
+        y = numerator.checked_div(denominator)?;
+
+        if y.max(y_prev) - y.min(y_prev) <= U256::one() {
+            break;
+        }
+    }
+
+    Some(y)
+}
+
+fn stable_pool_return(
+    pool: &PoolInfo,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+    index_in: usize,
+    index_out: usize,
+    amount_in: u128,
+) -> Option<u128> {
+    let mut decimals: Vec<u8> = Vec::new();
+    let mut c_amounts: Vec<U256> = Vec::new();
+
+    for (idx, token) in pool.token_account_ids.iter().enumerate() {
+        let token_decimals = token_metadata.get(token)?.decimals;
+        let scale = U256::exp10(STABLE_PRECISION.checked_sub(token_decimals)? as usize);
+
+        decimals.push(token_decimals);
+        c_amounts.push(U256::from(reserve(pool, idx)?).checked_mul(scale)?);
+    }
+
+    let scale_in = U256::exp10((STABLE_PRECISION - decimals[index_in]) as usize);
+    let scale_out = U256::exp10((STABLE_PRECISION - decimals[index_out]) as usize);
+
+    let x_c_amount =
+        c_amounts[index_in].checked_add(U256::from(amount_in).checked_mul(scale_in)?)?;
+    let y = compute_y(pool.amp, x_c_amount, &c_amounts, index_in, index_out)?;
+
+    let dy = c_amounts[index_out].checked_sub(y)?;
+    let fee = dy
+        .checked_mul(U256::from(pool.total_fee))?
+        .checked_div(U256::from(FEE_DIVISOR))?;
+    let amount_out = dy.checked_sub(fee)?.checked_div(scale_out)?;
+
+    u128::try_from(amount_out).ok()
+}
+
+pub fn get_pool_return(
+    pool: &PoolInfo,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+    token_in: &str,
+    token_out: &str,
+    amount_in: u128,
+) -> Option<u128> {
+    let index_in = position(pool, token_in)?;
+    let index_out = position(pool, token_out)?;
+
+    if index_in == index_out {
+        return None;
+    }
+
+    match pool.pool_kind.as_str() {
+        "SIMPLE_POOL" => simple_pool_return(pool, index_in, index_out, amount_in),
+        "STABLE_SWAP" => stable_pool_return(pool, token_metadata, index_in, index_out, amount_in),
+        _ => None,
+    }
+}
+
+// Stable pools scale every reserve by its decimals, so they can't be quoted without the metadata
+// of each of their tokens
+pub fn unknown_decimals<'a>(
+    pool: &'a PoolInfo,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+) -> Option<&'a String> {
+    if pool.pool_kind != "STABLE_SWAP" {
+        return None;
+    }
+
+    pool.token_account_ids
+        .iter()
+        .find(|token| !token_metadata.contains_key(*token))
+}
+
+// Compares the execution rate with the rate of a trade small enough not to move the pool
+pub fn get_price_impact(
+    pool: &PoolInfo,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+    token_in: &str,
+    token_out: &str,
+    amount_in: u128,
+    amount_out: u128,
+) -> Option<f64> {
+    let in_balance = reserve(pool, position(pool, token_in)?)?;
+    let probe_in = (in_balance / 1_000_000).max(1);
+    let probe_out = get_pool_return(pool, token_metadata, token_in, token_out, probe_in)?;
+
+    if probe_out == 0 || amount_in == 0 {
+        return None;
+    }
+
+    let spot_rate = probe_out as f64 / probe_in as f64;
+    let execution_rate = amount_out as f64 / amount_in as f64;
+
+    Some((1.0 - execution_rate / spot_rate).max(0.0))
+}
+
+pub fn get_swap_quote(
+    pool: &PoolInfo,
+    token_metadata: &BTreeMap<String, FungibleTokenMetadata>,
+    token_in: &str,
+    token_out: &str,
+    amount_in: u128,
+) -> Option<SwapQuote> {
+    let amount_out = get_pool_return(pool, token_metadata, token_in, token_out, amount_in)?;
+    let price_impact = get_price_impact(
+        pool,
+        token_metadata,
+        token_in,
+        token_out,
+        amount_in,
+        amount_out,
+    )
+    .unwrap_or_default();
+
+    Some(SwapQuote {
+        pool_id: pool.id?,
+        token_in: token_in.to_string(),
+        token_out: token_out.to_string(),
+        amount_in: amount_in.to_string(),
+        amount_out: amount_out.to_string(),
+        price_impact,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(kind: &str, tokens: &[&str], amounts: &[&str], total_fee: u32, amp: u64) -> PoolInfo {
+        PoolInfo {
+            id: Some(0),
+            pool_kind: kind.to_string(),
+            token_account_ids: tokens.iter().map(|token| token.to_string()).collect(),
+            amounts: amounts.iter().map(|amount| amount.to_string()).collect(),
+            total_fee,
+            shares_total_supply: String::from("0"),
+            amp,
+            farming: None,
+            token_symbols: None,
+            tvl: None,
+        }
+    }
+
+    fn metadata(tokens: &[(&str, u8)]) -> BTreeMap<String, FungibleTokenMetadata> {
+        tokens
+            .iter()
+            .map(|(token, decimals)| {
+                let metadata = FungibleTokenMetadata {
+                    spec: String::from("ft-1.0.0"),
+                    name: token.to_string(),
+                    symbol: token.to_string(),
+                    icon: None,
+                    reference: None,
+                    reference_hash: None,
+                    decimals: *decimals,
+                    price: None,
+                };
+                (token.to_string(), metadata)
+            })
+            .collect()
+    }
+
+    // 1000 NEAR / 3000 USDT at 0.3%, values from the contract's integer formula
+    fn simple_pool() -> PoolInfo {
+        pool(
+            "SIMPLE_POOL",
+            &["wrap.testnet", "usdt.testnet"],
+            &["1000000000000000000000000000", "3000000000"],
+            30,
+            0,
+        )
+    }
+
+    // 1M USDC (6 decimals) / 1M DAI (18 decimals), amp 240 and 0.05%
+    fn stable_pool() -> PoolInfo {
+        pool(
+            "STABLE_SWAP",
+            &["usdc.testnet", "dai.testnet"],
+            &["1000000000000", "1000000000000000000000000"],
+            5,
+            240,
+        )
+    }
+
+    #[test]
+    fn simple_pool_matches_get_return() {
+        let tokens = BTreeMap::new();
+        let pool = simple_pool();
+
+        assert_eq!(
+            get_pool_return(
+                &pool,
+                &tokens,
+                "wrap.testnet",
+                "usdt.testnet",
+                1_000_000_000_000_000_000_000_000
+            ),
+            Some(2_988_020)
+        );
+        assert_eq!(
+            get_pool_return(&pool, &tokens, "wrap.testnet", "usdt.testnet", 0),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn stable_pool_matches_get_return() {
+        let tokens = metadata(&[("usdc.testnet", 6), ("dai.testnet", 18)]);
+        let pool = stable_pool();
+
+        assert_eq!(
+            get_pool_return(&pool, &tokens, "usdc.testnet", "dai.testnet", 1_000_000_000),
+            Some(999_497_922_039_672_796_428)
+        );
+        assert_eq!(
+            get_pool_return(
+                &pool,
+                &tokens,
+                "dai.testnet",
+                "usdc.testnet",
+                1_000_000_000_000_000_000_000
+            ),
+            Some(999_497_922)
+        );
+    }
+
+    #[test]
+    fn invalid_pools_return_none() {
+        let tokens = metadata(&[("usdc.testnet", 6), ("dai.testnet", 18)]);
+
+        let mut pool = simple_pool();
+        pool.total_fee = 10_001;
+        assert_eq!(
+            get_pool_return(&pool, &tokens, "wrap.testnet", "usdt.testnet", 1),
+            None
+        );
+
+        let mut pool = stable_pool();
+        pool.amp = 0;
+        assert_eq!(
+            get_pool_return(&pool, &tokens, "usdc.testnet", "dai.testnet", 1),
+            None
+        );
+    }
+
+    // amount_with_fee * out_balance doesn't fit in 256 bits
+    #[test]
+    fn overflowing_amounts_return_none() {
+        let tokens = BTreeMap::new();
+        let max = u128::MAX.to_string();
+        let pool = pool(
+            "SIMPLE_POOL",
+            &["wrap.testnet", "usdt.testnet"],
+            &["1", &max],
+            30,
+            0,
+        );
+
+        assert_eq!(
+            get_pool_return(&pool, &tokens, "wrap.testnet", "usdt.testnet", u128::MAX),
+            None
+        );
+    }
+}
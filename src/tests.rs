@@ -0,0 +1,549 @@
+use crate::*;
+
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::Value;
+use tokio::runtime::Runtime;
+
+/***************************
+    Test support
+****************************/
+
+// One runtime for every test: the mock RPC and the pooled RPC connections live on it and would
+// go away with a runtime per test
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+static MOCK_RPC: OnceLock<String> = OnceLock::new();
+
+// Points the network at the mock RPC, before anything reads `network()` or `rpc()`
+fn setup() {
+    MOCK_RPC.get_or_init(|| {
+        // Tests share the endpoint, a rate limit would only slow them down
+        std::env::set_var("RPC_RATE_LIMIT", "0");
+
+        let mut network = NetworkConfig::from_env().expect("invalid network configuration");
+        let data = MockRpcData::fixture(&network.exchange_contract);
+        let url = start_mock_rpc(data, 0).expect("can't start the mock RPC");
+
+        network.rpc_url = url.clone();
+        init_network(network);
+
+        url
+    });
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let runtime = RUNTIME.get_or_init(|| Runtime::new().expect("can't start the test runtime"));
+
+    runtime.block_on(async {
+        setup();
+        future.await
+    })
+}
+
+// Keeps the latest write of each dataset, history isn't kept
+#[derive(Default)]
+pub struct MemoryStore {
+    tokens: Mutex<BTreeMap<String, FungibleTokenMetadata>>,
+    farms: Mutex<BTreeMap<String, FarmInfo>>,
+    pools: Mutex<BTreeMap<u64, PoolInfo>>,
+    prices: Mutex<BTreeMap<String, TokenInfo>>,
+    blocks: Mutex<BTreeMap<String, BlockInfo>>,
+}
+
+impl MemoryStore {
+    fn update_block(&self, dataset: &Dataset, block: &BlockInfo) {
+        self.blocks
+            .lock()
+            .unwrap()
+            .insert(dataset.value().to_string(), block.clone());
+    }
+}
+
+#[rocket::async_trait]
+impl Store for MemoryStore {
+    fn name(&self) -> String {
+        String::from("memory")
+    }
+
+    async fn store_tokens(
+        &self,
+        block: &BlockInfo,
+        tokens: &BTreeMap<String, FungibleTokenMetadata>,
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        let failed_tokens = failed_items(failures, Methods::FtMetadata);
+
+        let mut stored = self.tokens.lock().unwrap();
+        stored.retain(|token, _| failed_tokens.contains(token));
+        stored.extend(tokens.clone());
+        self.update_block(&Dataset::Tokens, block);
+
+        Ok(())
+    }
+
+    async fn store_farms(
+        &self,
+        block: &BlockInfo,
+        farms: &[FarmInfo],
+        failures: &[FetchFailure],
+    ) -> IndexerResult<()> {
+        let failed_seeds = failed_items(failures, Methods::ListFarmsBySeeds);
+
+        let mut stored = self.farms.lock().unwrap();
+        stored.retain(|_, farm| failed_seeds.contains(&farm.seed_id));
+        stored.extend(
+            farms
+                .iter()
+                .map(|farm| (farm.farm_id.clone(), farm.clone())),
+        );
+        self.update_block(&Dataset::Farms, block);
+
+        Ok(())
+    }
+
+    async fn store_pools(
+        &self,
+        block: &BlockInfo,
+        pools: &[PoolInfo],
+        prices: &BTreeMap<String, TokenInfo>,
+    ) -> IndexerResult<()> {
+        *self.pools.lock().unwrap() = pools
+            .iter()
+            .filter_map(|pool| Some((pool.id?, pool.clone())))
+            .collect();
+        *self.prices.lock().unwrap() = prices.clone();
+        self.update_block(&Dataset::Pools, block);
+
+        Ok(())
+    }
+
+    async fn get_tokens(&self) -> IndexerResult<BTreeMap<String, FungibleTokenMetadata>> {
+        Ok(self.tokens.lock().unwrap().clone())
+    }
+
+    async fn get_prices(&self) -> IndexerResult<BTreeMap<String, TokenInfo>> {
+        Ok(self.prices.lock().unwrap().clone())
+    }
+
+    async fn get_farms(&self) -> IndexerResult<Vec<FarmInfo>> {
+        Ok(self.farms.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn get_pools(&self) -> IndexerResult<Vec<PoolInfo>> {
+        Ok(self.pools.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn get_token(&self, account_id: &str) -> IndexerResult<Option<FungibleTokenMetadata>> {
+        Ok(self.tokens.lock().unwrap().get(account_id).cloned())
+    }
+
+    async fn get_farm(&self, farm_id: &str) -> IndexerResult<Option<FarmInfo>> {
+        Ok(self.farms.lock().unwrap().get(farm_id).cloned())
+    }
+
+    async fn get_pool(&self, pool_id: u64) -> IndexerResult<Option<PoolInfo>> {
+        Ok(self.pools.lock().unwrap().get(&pool_id).cloned())
+    }
+
+    async fn get_blocks(&self) -> IndexerResult<BTreeMap<String, BlockInfo>> {
+        Ok(self.blocks.lock().unwrap().clone())
+    }
+
+    async fn get_pool_history(
+        &self,
+        _pool_id: u64,
+        _from: Option<u64>,
+        _to: Option<u64>,
+    ) -> IndexerResult<Vec<PoolSnapshot>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_farm_history(
+        &self,
+        _farm_id: &str,
+        _from: Option<u64>,
+        _to: Option<u64>,
+    ) -> IndexerResult<Vec<FarmSnapshot>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_token_history(
+        &self,
+        _account_id: &str,
+        _from: Option<u64>,
+        _to: Option<u64>,
+    ) -> IndexerResult<Vec<TokenSnapshot>> {
+        Ok(Vec::new())
+    }
+}
+
+// The server as `rocket()` builds it, around an empty in-memory store and a stopped scheduler.
+// Every dataset is refreshed from the mock RPC before the client is returned
+async fn refreshed_client() -> Client {
+    let scheduler_config = SchedulerConfig {
+        enabled: false,
+        tokens_interval: Duration::ZERO,
+        farms_interval: Duration::ZERO,
+        pools_interval: Duration::ZERO,
+        jitter: Duration::ZERO,
+        metadata_ttl: Duration::from_secs(60),
+    };
+    let health_config = HealthConfig {
+        timeout: Duration::from_secs(2),
+        staleness_factor: 3,
+    };
+    let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+
+    let rocket = build_rocket(store, None, None, scheduler_config, health_config);
+    let client = Client::tracked(rocket).await.expect("valid rocket");
+
+    let scheduler = client
+        .rocket()
+        .state::<Arc<Scheduler>>()
+        .expect("managed scheduler")
+        .clone();
+    for dataset in Dataset::all() {
+        scheduler.clone().run(dataset).await;
+    }
+
+    client
+}
+
+async fn json_body(response: LocalResponse<'_>) -> Value {
+    response.into_json::<Value>().await.expect("JSON body")
+}
+
+fn is_unlisted_failure(failure: &FetchFailure) -> bool {
+    failure.item == "unlisted.testnet" && failure.method == Methods::FtMetadata.value()
+}
+
+/***************************
+    Fetchers
+****************************/
+
+// Also checks that the canned header deserializes into a `BlockView`
+#[test]
+fn final_block_comes_from_mock_rpc() {
+    let block = block_on(get_final_block(&Dataset::Tokens)).expect("final block");
+
+    assert_eq!(block.height, 100_000_000);
+    assert!(block.timestamp_secs() > 0);
+}
+
+#[test]
+fn pools_are_fetched_and_priced() {
+    let (pools, prices, failures) = block_on(async {
+        let block = get_final_block(&Dataset::Pools).await?;
+        let mut failures: Vec<FetchFailure> = Vec::new();
+        let (pools, prices) = get_pools(
+            &block,
+            &BTreeMap::new(),
+            &TokenMetadataCache::new(Duration::ZERO),
+            &mut failures,
+        )
+        .await?;
+
+        IndexerResult::Ok((pools, prices, failures))
+    })
+    .expect("pools");
+
+    let ids: Vec<Option<u64>> = pools.iter().map(|pool| pool.id).collect();
+    assert_eq!(ids, vec![Some(0), Some(1), Some(2)]);
+
+    let farming: Vec<Option<bool>> = pools.iter().map(|pool| pool.farming).collect();
+    assert_eq!(farming, vec![Some(false), Some(true), Some(false)]);
+
+    // 500k USDT against 100k wNEAR
+    let wrap_price = prices["wrap.testnet"].price.parse::<f64>().unwrap();
+    assert!((wrap_price - 5.0).abs() < 0.01, "wNEAR at {}", wrap_price);
+    assert!(!prices.contains_key("unlisted.testnet"));
+
+    // The token without metadata is left out instead of failing the refresh
+    assert_eq!(pools[2].token_symbols, Some(vec![String::from("wNEAR")]));
+    assert_eq!(failures.len(), 1);
+    assert!(is_unlisted_failure(&failures[0]));
+}
+
+#[test]
+fn farms_are_fetched_by_seed() {
+    let (farms, failures) = block_on(async {
+        let block = get_final_block(&Dataset::Farms).await?;
+        let mut failures: Vec<FetchFailure> = Vec::new();
+        let farms = get_farms(&block, &mut failures).await?;
+
+        IndexerResult::Ok((farms, failures))
+    })
+    .expect("farms");
+
+    assert_eq!(farms.len(), 1);
+    assert_eq!(seed_pool_id(&farms[0].seed_id), Some(1));
+    assert_eq!(farms[0].reward_token, "wrap.testnet");
+    assert!(failures.is_empty());
+}
+
+#[test]
+fn refresh_dataset_stores_every_dataset() {
+    let store = MemoryStore::default();
+    let feed = UpdateFeed::new();
+    let metadata = TokenMetadataCache::new(Duration::from_secs(60));
+
+    let failures = block_on(async {
+        let mut failures: BTreeMap<String, Vec<FetchFailure>> = BTreeMap::new();
+
+        for dataset in Dataset::all() {
+            let (_, dataset_failures) = refresh_dataset(&store, &feed, &metadata, &dataset).await?;
+            failures.insert(dataset.value().to_string(), dataset_failures);
+        }
+
+        IndexerResult::Ok(failures)
+    })
+    .expect("refresh");
+
+    let (tokens, farms, pools, blocks) = block_on(async {
+        IndexerResult::Ok((
+            store.get_tokens().await?,
+            store.get_farms().await?,
+            store.get_pools().await?,
+            store.get_blocks().await?,
+        ))
+    })
+    .unwrap();
+
+    let whitelisted: Vec<&String> = tokens.keys().collect();
+    assert_eq!(
+        whitelisted,
+        vec!["usdc.fakes.testnet", "usdt.fakes.testnet", "wrap.testnet"]
+    );
+    assert_eq!(farms.len(), 1);
+    assert_eq!(pools.len(), 3);
+    assert_eq!(blocks.len(), 3);
+
+    assert!(failures[Dataset::Tokens.value()].is_empty());
+    assert!(failures[Dataset::Farms.value()].is_empty());
+    assert!(failures[Dataset::Pools.value()]
+        .iter()
+        .any(is_unlisted_failure));
+}
+
+/***************************
+    Routes
+****************************/
+
+#[test]
+fn list_pools_serves_refreshed_pools() {
+    block_on(async {
+        let client = refreshed_client().await;
+
+        let response = client.get("/list-pools").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("3"));
+
+        let pools = response.into_json::<Vec<PoolInfo>>().await.unwrap();
+        assert_eq!(pools.len(), 3);
+
+        let response = client
+            .get("/list-pools?token=usdc.fakes.testnet")
+            .dispatch()
+            .await;
+        let pools = response.into_json::<Vec<PoolInfo>>().await.unwrap();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].id, Some(1));
+    });
+}
+
+#[test]
+fn get_pool_returns_one_pool_or_not_found() {
+    block_on(async {
+        let client = refreshed_client().await;
+
+        let response = client.get("/pools/1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let pool = response.into_json::<PoolInfo>().await.unwrap();
+        assert_eq!(pool.pool_kind, "STABLE_SWAP");
+
+        let response = client.get("/pools/9").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json_body(response).await["error"], "not_found");
+    });
+}
+
+#[test]
+fn quote_uses_cached_pool() {
+    block_on(async {
+        let client = refreshed_client().await;
+
+        // 1 wNEAR into 100k wNEAR / 500k USDT at 0.3%
+        let response = client
+            .get("/quote?pool_id=0&token_in=wrap.testnet&token_out=usdt.fakes.testnet&amount_in=1000000000000000000000000")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let quote = response.into_json::<SwapQuote>().await.unwrap();
+        assert_eq!(quote.amount_out, "4984950");
+
+        let response = client
+            .get("/quote?pool_id=0&token_in=wrap.testnet&token_out=usdt.fakes.testnet&amount_in=lots")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(response).await["error"], "bad_request");
+    });
+}
+
+#[test]
+fn quote_falls_back_to_fetched_metadata() {
+    block_on(async {
+        let client = refreshed_client().await;
+        let store = client
+            .rocket()
+            .state::<Arc<dyn Store>>()
+            .expect("managed store")
+            .clone();
+
+        // USDC leaves the whitelist, the stable pool can't scale its reserve without it
+        let block = store.get_blocks().await.unwrap()[Dataset::Tokens.value()].clone();
+        let mut tokens = store.get_tokens().await.unwrap();
+        let usdc = tokens.remove("usdc.fakes.testnet").unwrap();
+        store.store_tokens(&block, &tokens, &[]).await.unwrap();
+
+        let stable_quote =
+            "/quote?pool_id=1&token_in=usdt.fakes.testnet&token_out=usdc.fakes.testnet&amount_in=1000000";
+
+        let response = client.get(stable_quote).dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(json_body(response).await["error"], "unavailable");
+
+        client
+            .rocket()
+            .state::<Arc<TokenMetadataCache>>()
+            .expect("managed metadata")
+            .insert(&BTreeMap::from([(
+                String::from("usdc.fakes.testnet"),
+                usdc,
+            )]));
+
+        let response = client.get(stable_quote).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    });
+}
+
+#[test]
+fn failures_lists_token_without_metadata() {
+    block_on(async {
+        let client = refreshed_client().await;
+
+        let response = client.get("/failures").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let failures = response
+            .into_json::<BTreeMap<String, Vec<FetchFailure>>>()
+            .await
+            .unwrap();
+        assert!(failures[Dataset::Pools.value()]
+            .iter()
+            .any(is_unlisted_failure));
+        assert!(failures[Dataset::Farms.value()].is_empty());
+    });
+}
+
+#[test]
+fn unknown_routes_and_bad_parameters_are_json_errors() {
+    block_on(async {
+        let client = refreshed_client().await;
+
+        for uri in [
+            "/nowhere",
+            "/pools/abc",
+            "/quote?pool_id=abc&token_in=a&token_out=b&amount_in=1",
+            "/quote?pool_id=0",
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert!(response.status().class().is_client_error(), "{}", uri);
+
+            let body = json_body(response).await;
+            assert!(body["error"].is_string(), "{}", uri);
+            assert!(body["message"].is_string(), "{}", uri);
+        }
+    });
+}
+
+/***************************
+    Update feed
+****************************/
+
+fn farm(farm_id: &str, seed_id: &str, cur_round: u64) -> FarmInfo {
+    serde_json::from_value(json!({
+        "farm_id": farm_id,
+        "farm_kind": "SIMPLE_FARM",
+        "farm_status": "Running",
+        "seed_id": seed_id,
+        "reward_token": "wrap.testnet",
+        "start_at": 0,
+        "reward_per_session": "1",
+        "session_interval": 3600,
+        "total_reward": "100",
+        "cur_round": cur_round,
+        "last_round": cur_round,
+        "claimed_reward": "0",
+        "unclaimed_reward": "0",
+        "beneficiary_reward": "0",
+        "seed_amount": null,
+        "apr": null,
+    }))
+    .unwrap()
+}
+
+fn block(height: u64) -> BlockInfo {
+    BlockInfo {
+        height,
+        hash: String::new(),
+        timestamp: 0,
+    }
+}
+
+#[test]
+fn feed_publishes_changes_and_keeps_farms_of_failed_seeds() {
+    let feed = UpdateFeed::new();
+    let mut receiver = feed.subscribe();
+
+    let a = farm("a.testnet#0", "a.testnet", 1);
+    let b = farm("b.testnet#0", "b.testnet", 1);
+    feed.publish_farms(&block(1), &[a.clone(), b.clone()], &[]);
+
+    let event = receiver.try_recv().unwrap();
+    assert_eq!(event.farms.len(), 2);
+    assert!(event.removed.is_empty());
+
+    // Nothing changed, nothing is sent
+    feed.publish_farms(&block(2), &[a.clone(), b.clone()], &[]);
+    assert!(receiver.try_recv().is_err());
+
+    // The seed of `b` failed to load: `b` isn't reported as removed
+    let failure = FetchFailure::new(
+        Methods::ListFarmsBySeeds,
+        "b.testnet",
+        &IndexerError::Rpc(String::from("timed out")),
+    );
+    let a = farm("a.testnet#0", "a.testnet", 2);
+    feed.publish_farms(&block(3), std::slice::from_ref(&a), &[failure]);
+
+    let event = receiver.try_recv().unwrap();
+    assert_eq!(event.farms, vec![a.clone()]);
+    assert!(event.removed.is_empty());
+
+    // Once the seed loads again an unchanged `b` isn't sent, and a farm that is really gone is
+    // removed exactly once
+    feed.publish_farms(&block(4), std::slice::from_ref(&b), &[]);
+
+    let event = receiver.try_recv().unwrap();
+    assert!(event.farms.is_empty());
+    assert_eq!(event.removed, vec![String::from("a.testnet#0")]);
+
+    feed.publish_farms(&block(5), &[b], &[]);
+    assert!(receiver.try_recv().is_err());
+}
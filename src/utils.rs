@@ -1,21 +1,78 @@
 use crate::*;
 
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Unix timestamp in seconds
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn parse_amount(amount: &str) -> IndexerResult<u128> {
+    amount
+        .parse::<u128>()
+        .map_err(|err| IndexerError::Decode(format!("invalid amount {}: {}", amount, err)))
+}
+
+pub fn parse_account_id(account_id: &str) -> IndexerResult<AccountId> {
+    account_id
+        .parse::<AccountId>()
+        .map_err(|err| IndexerError::Decode(format!("invalid account id {}: {}", account_id, err)))
+}
+
+// Resolved at the start of each refresh of `dataset`, which is what replay pins it by
+pub async fn get_final_block(dataset: &Dataset) -> IndexerResult<BlockInfo> {
+    if let Some(block) = recorder().replay_block(dataset) {
+        return block;
+    }
+
+    let started = Instant::now();
+    let response = rpc_pool()
+        .call(|| methods::block::RpcBlockRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+        })
+        .await;
+    metrics().observe_rpc("block", started, &response);
+    let response = response?;
+
+    let block = BlockInfo {
+        height: response.header.height,
+        hash: response.header.hash.to_string(),
+        timestamp: response.header.timestamp,
+    };
+    recorder().record_block(dataset, &block);
+
+    Ok(block)
+}
+
 pub async fn call_view(
+    block: &BlockInfo,
     contract: &str,
     method_name: String,
     args: FunctionArgs,
-) -> Result<RpcQueryResponse, Box<dyn std::error::Error>> {
-    let client = JsonRpcClient::connect("https://rpc.testnet.near.org");
-
-    let request = methods::query::RpcQueryRequest {
-        block_reference: BlockReference::Finality(Finality::Final),
-        request: QueryRequest::CallFunction {
-            account_id: contract.parse()?,
-            method_name: method_name,
-            args: args,
-        },
-    };
+) -> IndexerResult<RpcQueryResponse> {
+    if let Some(response) = recorder().replay_call(block, contract, &method_name, &args) {
+        return response;
+    }
+
+    let account_id = parse_account_id(contract)?;
+
+    let started = Instant::now();
+    let response = rpc_pool()
+        .call(|| methods::query::RpcQueryRequest {
+            block_reference: BlockReference::BlockId(BlockId::Height(block.height)),
+            request: QueryRequest::CallFunction {
+                account_id: account_id.clone(),
+                method_name: method_name.clone(),
+                args: args.clone(),
+            },
+        })
+        .await;
+
+    metrics().observe_rpc(&method_name, started, &response);
+    recorder().record_call(block, contract, &method_name, &args, &response);
 
-    let response = client.call(request).await?;
-    Ok(response)
+    response
 }